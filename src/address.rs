@@ -1,19 +1,270 @@
+use std::fmt;
+use std::str::FromStr;
+
 use anyhow::{Result,bail};
-use bitcoin::bech32::Hrp;
-use bitcoin::blockdata::opcodes::OP_0;
+use bitcoin::address::{AddressEncoding,Payload};
+use bitcoin::bech32::primitives::decode::{SegwitHrpstring,SegwitHrpstringError};
+use bitcoin::hashes::Hash;
 use bitcoin::script::{Instruction, PushBytes};
+use bitcoin::{PubkeyHash, ScriptHash, WPubkeyHash, WScriptHash, WitnessProgram, WitnessVersion};
 use bitcoin::{Script, ScriptBuf};
 
+use crate::network;
 use crate::types::VoteOld;
 
-fn encode_address_payload(payload: &bitcoin::address::Payload) -> String {
-    bitcoin::address::AddressEncoding{
-        payload: payload,
-        // TODO config
-        p2pkh_prefix: 0x75,
-        p2sh_prefix: 0x38,
-        hrp: Hrp::parse("pkt").unwrap(),
-    }.to_string()
+/// Any kind of address on the current network, see network::current()
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PktAddress {
+    /// Legacy p... address
+    P2pkh(PubkeyHash),
+    /// Legacy script address
+    P2sh(ScriptHash),
+    /// Segwit pkt1q... address of a key
+    P2wpkh(WPubkeyHash),
+    /// Segwit pkt1q... address of a script
+    P2wsh(WScriptHash),
+    /// Witness version 1 or later, encoded with bech32m
+    Witness(WitnessProgram),
+}
+
+impl PktAddress {
+    /// The address which an output script pays to, if it has one
+    pub fn from_script(script: &Script) -> Result<Self> {
+        match Payload::from_script(script) {
+            Ok(Payload::PubkeyHash(h)) => Ok(PktAddress::P2pkh(h)),
+            Ok(Payload::ScriptHash(h)) => Ok(PktAddress::P2sh(h)),
+            Ok(Payload::WitnessProgram(wp)) => Self::from_witness_program(wp),
+            Ok(_) => bail!("Unsupported kind of address"),
+            Err(e) => bail!("Script has no address: {e}"),
+        }
+    }
+
+    fn from_witness_program(wp: WitnessProgram) -> Result<Self> {
+        if wp.version() != WitnessVersion::V0 {
+            return Ok(PktAddress::Witness(wp));
+        }
+        let program = wp.program().as_bytes();
+        Ok(match program.len() {
+            20 => PktAddress::P2wpkh(WPubkeyHash::from_slice(program)?),
+            32 => PktAddress::P2wsh(WScriptHash::from_slice(program)?),
+            n => bail!("Invalid segwit v0 program length {n}"),
+        })
+    }
+
+    fn from_bech32(s: &str) -> Result<Self> {
+        let (_hrp, version, data) = match bitcoin::bech32::segwit::decode(s) {
+            Ok(x) => x,
+            Err(e) => bail!("Invalid bech32 address: {:#}", anyhow::Error::from(e)),
+        };
+        let version = WitnessVersion::try_from(version)?;
+        let wp = match WitnessProgram::new(version, data) {
+            Ok(wp) => wp,
+            Err(e) => bail!("Invalid witness program: {e}"),
+        };
+        Self::from_witness_program(wp)
+    }
+
+    fn from_base58(s: &str) -> Result<Self> {
+        let params = network::current();
+        if s.len() > 50 {
+            bail!("Address is too long to be b58");
+        }
+        let data = match bitcoin::base58::decode_check(s) {
+            Ok(data) => data,
+            Err(e) => bail!("Invalid address: {e}"),
+        };
+        if data.len() != 21 {
+            bail!("Base58 decode of address is not 21 bytes");
+        }
+        if data[0] == params.p2pkh_prefix {
+            Ok(PktAddress::P2pkh(PubkeyHash::from_slice(&data[1..])?))
+        } else if data[0] == params.p2sh_prefix {
+            Ok(PktAddress::P2sh(ScriptHash::from_slice(&data[1..])?))
+        } else {
+            bail!("First byte of decoded b58 not recognized, is this an address of another network?");
+        }
+    }
+
+    pub fn payload(&self) -> Payload {
+        match self {
+            PktAddress::P2pkh(h) => Payload::PubkeyHash(*h),
+            PktAddress::P2sh(h) => Payload::ScriptHash(*h),
+            PktAddress::P2wpkh(h) => Payload::WitnessProgram(
+                WitnessProgram::new(WitnessVersion::V0, h.to_byte_array()).unwrap()),
+            PktAddress::P2wsh(h) => Payload::WitnessProgram(
+                WitnessProgram::new(WitnessVersion::V0, h.to_byte_array()).unwrap()),
+            PktAddress::Witness(wp) => Payload::WitnessProgram(wp.clone()),
+        }
+    }
+
+    /// The output script which pays to the address
+    pub fn script_pubkey(&self) -> ScriptBuf {
+        self.payload().script_pubkey()
+    }
+}
+
+impl FromStr for PktAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self::parse(s)?)
+    }
+}
+
+/// Characters which may appear after the separator of a bech32 address
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Characters which may appear in a base58 address
+const BASE58_CHARSET: &str = "123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Why a typed address is not valid, along with the characters which are probably wrong
+#[derive(Debug)]
+pub struct AddressError {
+    pub message: String,
+    /// Indexes of the suspicious characters in the trimmed address
+    pub positions: Vec<usize>,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+fn address_error(message: impl Into<String>, positions: Vec<usize>) -> AddressError {
+    AddressError{ message: message.into(), positions }
+}
+
+/// Look for a single wrong, extra or missing character in s[start..] which would make it valid.
+fn locate_typo(s: &str, start: usize, charset: &str, valid: impl Fn(&str) -> bool) -> Option<AddressError> {
+    let chars: Vec<char> = s.chars().collect();
+    let with = |i: usize, skip: usize, c: Option<char>| -> String {
+        chars[..i].iter().chain(c.iter()).chain(chars[i + skip..].iter()).collect()
+    };
+    let mut wrong = Vec::new();
+    for (i, &orig) in chars.iter().enumerate().skip(start) {
+        for c in charset.chars().filter(|c|*c != orig) {
+            if valid(&with(i, 1, Some(c))) {
+                wrong.push((i, c));
+            }
+        }
+    }
+    match &wrong[..] {
+        [] => {}
+        [(i, c)] => return Some(address_error(
+            format!("Character {} is probably mistyped, it might be '{c}' rather than '{}'", i + 1, chars[*i]),
+            vec![*i])),
+        _ => {
+            let mut positions: Vec<usize> = wrong.iter().map(|(i, _)|*i).collect();
+            positions.dedup();
+            return Some(address_error("One of the highlighted characters is probably mistyped", positions));
+        }
+    }
+    if let Some(i) = (start..chars.len()).find(|i|valid(&with(*i, 1, None))) {
+        return Some(address_error(format!("Character {} seems to be typed twice or by mistake", i + 1), vec![i]));
+    }
+    for i in start..=chars.len() {
+        if charset.chars().any(|c|valid(&with(i, 0, Some(c)))) {
+            let near = if i < chars.len() { vec![i] } else { vec![i - 1] };
+            return Some(address_error(format!("A character seems to be missing at position {}", i + 1), near));
+        }
+    }
+    None
+}
+
+impl PktAddress {
+    /// Parse an address, saying where the problem is if it is not valid
+    pub fn parse(s: &str) -> std::result::Result<Self, AddressError> {
+        let params = network::current();
+        let s = s.trim();
+        let chars: Vec<char> = s.chars().collect();
+        if chars.is_empty() {
+            return Err(address_error("The address is empty", Vec::new()));
+        }
+        let bad: Vec<usize> = (0..chars.len()).filter(|i|!chars[*i].is_ascii_alphanumeric()).collect();
+        if !bad.is_empty() {
+            return Err(address_error("An address only contains letters and digits", bad));
+        }
+        // Some legacy addresses also begin with pkt1 in mixed case, so try base58 first
+        let b58_err = match Self::from_base58(s) {
+            Ok(addr) => return Ok(addr),
+            Err(e) => e,
+        };
+        let lower = s.to_ascii_lowercase();
+        if lower.starts_with(&format!("{}1", params.hrp)) {
+            return Self::parse_bech32(s, params.hrp.len() + 1);
+        }
+
+        // A bech32 address with the wrong prefix, either mistyped or of another network
+        if let Some(sep) = lower.rfind('1') {
+            let (hrp, data) = (&lower[..sep], &lower[sep + 1..]);
+            if sep > 0 && data.len() > 6 && hrp.chars().all(|c|c.is_ascii_alphabetic()) &&
+                data.chars().all(|c|BECH32_CHARSET.contains(c))
+            {
+                let positions = if hrp.len() == params.hrp.len() {
+                    hrp.chars().zip(params.hrp.chars()).enumerate()
+                        .filter(|(_, (a, b))|a != b).map(|(i, _)|i).collect()
+                } else {
+                    (0..sep).collect()
+                };
+                return Err(address_error(
+                    format!("The address begins with {hrp}1 rather than {}1, is it an address of another network?", params.hrp),
+                    positions));
+            }
+        }
+
+        let bad: Vec<usize> = (0..chars.len()).filter(|i|!BASE58_CHARSET.contains(chars[*i])).collect();
+        if !bad.is_empty() {
+            return Err(address_error("0, O, I and l can not appear in a legacy address", bad));
+        }
+        if let Some(e) = locate_typo(s, 0, BASE58_CHARSET, |c|Self::from_base58(c).is_ok()) {
+            return Err(e);
+        }
+        Err(address_error(b58_err.to_string(), Vec::new()))
+    }
+
+    /// Parse a bech32 address which has the right prefix, data begins at character data_start
+    fn parse_bech32(s: &str, data_start: usize) -> std::result::Result<Self, AddressError> {
+        let chars: Vec<char> = s.chars().collect();
+        let upper: Vec<usize> = (0..chars.len()).filter(|i|chars[*i].is_ascii_uppercase()).collect();
+        let lower: Vec<usize> = (0..chars.len()).filter(|i|chars[*i].is_ascii_lowercase()).collect();
+        if !upper.is_empty() && !lower.is_empty() {
+            return Err(address_error("The address mixes upper and lower case letters",
+                if upper.len() < lower.len() { upper } else { lower }));
+        }
+        let s = s.to_ascii_lowercase();
+        let bad: Vec<usize> = (data_start..chars.len())
+            .filter(|i|!BECH32_CHARSET.contains(chars[*i].to_ascii_lowercase())).collect();
+        if !bad.is_empty() {
+            return Err(address_error(
+                format!("1, b, i and o can not appear after the {} at the beginning", &s[..data_start]), bad));
+        }
+        let err = match Self::from_bech32(&s) {
+            Ok(addr) => return Ok(addr),
+            Err(e) => e,
+        };
+        if let Some(e) = locate_typo(&s, data_start, BECH32_CHARSET, |c|Self::from_bech32(c).is_ok()) {
+            return Err(e);
+        }
+        if let Err(SegwitHrpstringError::Checksum(_)) = SegwitHrpstring::new(&s) {
+            return Err(address_error("The checksum of the address is wrong, more than one character is mistyped", Vec::new()));
+        }
+        Err(address_error(err.to_string(), Vec::new()))
+    }
+}
+
+impl fmt::Display for PktAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params = network::current();
+        AddressEncoding{
+            payload: &self.payload(),
+            p2pkh_prefix: params.p2pkh_prefix,
+            p2sh_prefix: params.p2sh_prefix,
+            hrp: params.hrp(),
+        }.fmt(f)
+    }
 }
 
 fn parse_vote_part_old(vote: &PushBytes) -> Option<String> {
@@ -138,19 +389,13 @@ const DATA_TYPES: DataTypes = DataTypes(&[
 ]);
 
 pub fn encode_from_pkscript(out: &bitcoin::script::ScriptBuf) -> (String, Option<Result<VoteOld>>) {
-    match  bitcoin::Address::from_script(out.as_script(), bitcoin::Network::Bitcoin) {
-        Ok(addr) => {
-            return (encode_address_payload(&addr.payload()), None);
-        }
-        Err(_) => {}
+    if let Ok(addr) = PktAddress::from_script(out) {
+        return (addr.to_string(), None);
     }
     // Try again but stripping any possible vote (old system)
     let (scr, vote) = split_vote_old(out);
-    match  bitcoin::Address::from_script(scr.as_script(), bitcoin::Network::Bitcoin) {
-        Ok(addr) => {
-            return (encode_address_payload(&addr.payload()), vote);
-        }
-        Err(_) => {}
+    if let Ok(addr) = PktAddress::from_script(&scr) {
+        return (addr.to_string(), vote);
     }
 
     if scr.is_op_return() && scr.instructions().count() == 2 {
@@ -159,4 +404,160 @@ pub fn encode_from_pkscript(out: &bitcoin::script::ScriptBuf) -> (String, Option
         }
     }
     (String::new() + "script:" + &b64(out.as_bytes()), vote)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use bitcoin::bech32::{Bech32,Bech32m,ByteIterExt,Fe32,Fe32IterExt};
+
+    use super::*;
+
+    /// Encode a witness program without checking that it is valid
+    fn bech32_address(version: u8, program: &[u8]) -> String {
+        let hrp = network::current().hrp();
+        let version = Fe32::try_from(version).unwrap();
+        if version == Fe32::Q {
+            program.iter().copied().bytes_to_fes().with_checksum::<Bech32>(&hrp)
+                .with_witness_version(version).chars().collect()
+        } else {
+            program.iter().copied().bytes_to_fes().with_checksum::<Bech32m>(&hrp)
+                .with_witness_version(version).chars().collect()
+        }
+    }
+
+    fn round_trip(script: ScriptBuf, prefix: &str) -> PktAddress {
+        let addr = PktAddress::from_script(&script).unwrap();
+        let s = addr.to_string();
+        assert!(s.starts_with(prefix), "{s} does not begin with {prefix}");
+        let parsed = PktAddress::parse(&s).unwrap();
+        assert_eq!(parsed, addr);
+        assert_eq!(parsed.script_pubkey(), script);
+        assert_eq!(parsed.to_string(), s);
+        parsed
+    }
+
+    #[test]
+    fn p2pkh_round_trip() {
+        let addr = round_trip(ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([1; 20])), "p");
+        assert!(matches!(addr, PktAddress::P2pkh(_)));
+    }
+
+    #[test]
+    fn p2sh_round_trip() {
+        let addr = round_trip(ScriptBuf::new_p2sh(&ScriptHash::from_byte_array([2; 20])), "P");
+        assert!(matches!(addr, PktAddress::P2sh(_)));
+    }
+
+    #[test]
+    fn p2wpkh_round_trip() {
+        let addr = round_trip(ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([3; 20])), "pkt1q");
+        assert!(matches!(addr, PktAddress::P2wpkh(_)));
+    }
+
+    #[test]
+    fn p2wsh_round_trip() {
+        let addr = round_trip(ScriptBuf::new_p2wsh(&WScriptHash::from_byte_array([4; 32])), "pkt1q");
+        assert!(matches!(addr, PktAddress::P2wsh(_)));
+    }
+
+    #[test]
+    fn bech32m_round_trip() {
+        let v1 = WitnessProgram::new(WitnessVersion::V1, [5; 32]).unwrap();
+        let addr = round_trip(ScriptBuf::new_witness_program(&v1), "pkt1p");
+        assert_eq!(addr, PktAddress::Witness(v1.clone()));
+        let v16 = WitnessProgram::new(WitnessVersion::V16, [6; 2]).unwrap();
+        let addr = round_trip(ScriptBuf::new_witness_program(&v16), "pkt1s");
+        assert_eq!(addr, PktAddress::Witness(v16));
+        assert_eq!(bech32_address(1, &[5; 32]), PktAddress::Witness(v1).to_string());
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let s = bech32_address(0, &[3; 20]);
+        assert!(PktAddress::parse(&s).is_ok());
+        // Two characters wrong, so it can not be fixed by changing one
+        let mut bad: Vec<char> = s.chars().collect();
+        let n = bad.len();
+        bad[n - 1] = if bad[n - 1] == 'q' { 'p' } else { 'q' };
+        bad[n - 10] = if bad[n - 10] == 'q' { 'p' } else { 'q' };
+        let err = PktAddress::parse(&bad.iter().collect::<String>()).unwrap_err();
+        assert!(err.message.contains("checksum"), "{err}");
+
+        let s = PktAddress::P2pkh(PubkeyHash::from_byte_array([1; 20])).to_string();
+        let mut bad: Vec<char> = s.chars().collect();
+        let n = bad.len();
+        bad[n - 1] = if bad[n - 1] == '2' { '3' } else { '2' };
+        bad[n - 2] = if bad[n - 2] == '2' { '3' } else { '2' };
+        assert!(PktAddress::parse(&bad.iter().collect::<String>()).is_err());
+    }
+
+    #[test]
+    fn bad_program_length() {
+        // Segwit v0 programs are 20 or 32 bytes
+        for len in [19, 21, 31, 33] {
+            assert!(PktAddress::parse(&bech32_address(0, &vec![7; len])).is_err(), "v0 of {len} bytes");
+        }
+        // Other versions are 2 to 40 bytes
+        for len in [1, 41] {
+            assert!(PktAddress::parse(&bech32_address(1, &vec![7; len])).is_err(), "v1 of {len} bytes");
+        }
+        let script = ScriptBuf::from_bytes([&[0x00, 25][..], &[7; 25]].concat());
+        assert!(PktAddress::from_script(&script).is_err());
+    }
+
+    const SEGWIT: &str = "pkt1qwednyrzer4z5gqwgc64zztxsrcd3syqvdqe5wt";
+    const LEGACY: &str = "pGLPBiUxKvdXh7Z7ebtXWHv8iq2ToqEq33";
+
+    fn positions(s: &str) -> Vec<usize> {
+        PktAddress::parse(s).unwrap_err().positions
+    }
+
+    fn substitute(s: &str, i: usize, c: char) -> String {
+        s.char_indices().map(|(j, x)|if i == j { c } else { x }).collect()
+    }
+
+    #[test]
+    fn typo_positions_segwit() {
+        assert!(PktAddress::parse(SEGWIT).is_ok());
+        assert_eq!(positions(&substitute(SEGWIT, 10, 'x')), vec![10]);
+        assert_eq!(positions(&format!("{}h{}", &SEGWIT[..20], &SEGWIT[20..])), vec![20]);
+        assert_eq!(positions(&format!("{}{}", &SEGWIT[..20], &SEGWIT[21..])), vec![20]);
+        // Missing at the end, the last character is highlighted
+        assert_eq!(positions(&SEGWIT[..SEGWIT.len() - 1]), vec![SEGWIT.len() - 2]);
+    }
+
+    #[test]
+    fn typo_positions_legacy() {
+        assert!(PktAddress::parse(LEGACY).is_ok());
+        assert_eq!(positions(&substitute(LEGACY, 10, 'e')), vec![10]);
+        assert_eq!(positions(&format!("{}a{}", &LEGACY[..15], &LEGACY[15..])), vec![15]);
+        assert_eq!(positions(&format!("{}{}", &LEGACY[..15], &LEGACY[16..])), vec![15]);
+        assert_eq!(positions(&substitute(LEGACY, 5, '0')), vec![5]);
+    }
+
+    #[test]
+    fn wrong_hrp_positions() {
+        assert_eq!(positions(&substitute(SEGWIT, 2, 'x')), vec![2]);
+        assert_eq!(positions(&SEGWIT.replacen("pkt", "bc", 1)), vec![0, 1]);
+        let err = PktAddress::parse(&SEGWIT.replacen("pkt", "tpk", 1)).unwrap_err();
+        assert_eq!(err.positions, vec![0, 1, 2]);
+        assert!(err.message.contains("another network"), "{err}");
+    }
+
+    #[test]
+    fn legacy_beginning_with_hrp() {
+        let addr = PktAddress::parse("pKT11DKgyZztdUzSv3aA7fb7SqEppF7bJd").unwrap();
+        assert!(matches!(addr, PktAddress::P2pkh(_)));
+        let addr = PktAddress::parse("PkT11C6YSEeqX1acA5DwBSbTR3NJw5qGJU").unwrap();
+        assert!(matches!(addr, PktAddress::P2sh(_)));
+    }
+
+    #[test]
+    fn mixed_case_positions() {
+        let s = SEGWIT.to_ascii_uppercase();
+        assert!(PktAddress::parse(&s).is_ok());
+        assert_eq!(positions(&substitute(SEGWIT, 5, 'W').replacen('e', "E", 1)), vec![5, 6]);
+        // Mostly upper case, so the lower case letters are highlighted, digits have no case
+        let lower: Vec<usize> = (30..SEGWIT.len()).filter(|i|SEGWIT.as_bytes()[*i].is_ascii_alphabetic()).collect();
+        assert_eq!(positions(&format!("{}{}", &s[..30], &SEGWIT[30..])), lower);
+    }
+}
@@ -0,0 +1,122 @@
+use bitcoin::key::Secp256k1;
+use bitcoin::key::secp256k1::All;
+use bitcoin::psbt;
+use bitcoin::FeeRate;
+use anyhow::{bail,Result};
+
+use crate::backend::ChainBackend;
+use crate::vote::Vote;
+use crate::wallet::{self,AddrInfo};
+
+/// Parse private keys, one per line. Blank lines and lines beginning with # are ignored. Gives
+/// every address of each key, use funded() to drop the ones without coins.
+pub fn parse_keys(text: &str, secp: &Secp256k1<All>) -> Result<Vec<Vec<AddrInfo>>> {
+    let mut out: Vec<Vec<AddrInfo>> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let addrs = match wallet::wif_key_to_addresses(line, secp) {
+            Ok(addrs) => addrs,
+            Err(e) => bail!("Private key on line {}: {e}", n + 1),
+        };
+        if !out.iter().flatten().any(|a|addrs.iter().any(|ai|a.addr_str == ai.addr_str)) {
+            out.push(addrs);
+        }
+    }
+    if out.is_empty() {
+        bail!("No private keys found");
+    }
+    Ok(out)
+}
+
+/// The addresses of each key which have coins, or the first address of a key if none of them
+/// has any, so that every key is in the batch once even if it can't vote.
+pub async fn funded(backend: &dyn ChainBackend, keys: Vec<Vec<AddrInfo>>) -> Vec<AddrInfo> {
+    let mut out = Vec::new();
+    for mut addrs in keys {
+        let mut with_coins = Vec::new();
+        for ai in &addrs {
+            // If the balance is unknown then let preparing the vote give the error
+            match backend.get_balance(&ai.addr_str).await {
+                Ok(balance) if balance == bitcoin::Amount::ZERO => {}
+                _ => with_coins.push(ai.clone()),
+            }
+        }
+        if with_coins.is_empty() && !addrs.is_empty() {
+            with_coins.push(addrs.swap_remove(0));
+        }
+        out.extend(with_coins);
+    }
+    out
+}
+
+/// Build the vote of one address of the batch
+pub async fn prepare(
+    backend: &dyn ChainBackend,
+    ai: &AddrInfo,
+    consolidate: bool,
+    fee_rate: FeeRate,
+    vote: &Vote,
+) -> Result<psbt::Psbt> {
+    let utxos = wallet::get_address_utxos(backend, &ai.addr_str).await?;
+    let mut psbt = wallet::make_vote(ai, &utxos, consolidate, fee_rate, vote)?;
+    wallet::add_non_witness_utxos(backend, &mut psbt).await?;
+    Ok(psbt)
+}
+
+/// Sign and send one vote of the batch
+pub async fn send(
+    backend: &dyn ChainBackend,
+    ai: &AddrInfo,
+    psbt: psbt::Psbt,
+    secp: &Secp256k1<All>,
+) -> Result<bitcoin::Transaction> {
+    let tx = wallet::sign_txn(ai, psbt, secp)?;
+    backend.bcast_transaction(&tx).await?;
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{Amount,TxIn,TxOut};
+    use crate::backend::mock::MockBackend;
+
+    fn wif(n: u8) -> String {
+        let sk = bitcoin::secp256k1::SecretKey::from_slice(&[n; 32]).unwrap();
+        wallet::encode_wif(&bitcoin::PrivateKey::new(sk, bitcoin::Network::Bitcoin))
+    }
+
+    #[test]
+    fn keys_are_read_from_the_text() {
+        let secp = Secp256k1::new();
+        let text = format!("# members\n{}\n\n  {}  \n{}\n", wif(1), wif(2), wif(1));
+        let keys = parse_keys(&text, &secp).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|addrs|addrs.len() == 2));
+
+        let err = parse_keys(&format!("{}\nnot a key", wif(1)), &secp).err().unwrap().to_string();
+        assert!(err.contains("line 2"), "{err}");
+        assert!(parse_keys("# nothing\n", &secp).is_err());
+    }
+
+    #[tokio::test]
+    async fn unfunded_addresses_are_dropped() {
+        let secp = Secp256k1::new();
+        let keys = parse_keys(&format!("{}\n{}", wif(1), wif(2)), &secp).unwrap();
+        let backend = MockBackend::default();
+        // Only the p2pkh address of the first key has coins
+        let paid = keys[0][1].clone();
+        backend.add(bitcoin::Transaction{
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut{ value: Amount::from_sat(1_000_000), script_pubkey: paid.change_script.clone() }],
+        }, Some(1));
+
+        let got: Vec<_> = funded(&backend, keys.clone()).await.into_iter().map(|ai|ai.addr_str).collect();
+        assert_eq!(got, [paid.addr_str.clone(), keys[1][0].addr_str.clone()]);
+    }
+}
@@ -0,0 +1,594 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::io::BufRead;
+
+use bitcoin::consensus::encode;
+use bitcoin::key::Secp256k1;
+use bitcoin::key::secp256k1::All;
+use bitcoin::psbt;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::OutPoint;
+use bitcoin::ScriptBuf;
+use bitcoin::TxOut;
+use anyhow::{bail,Result};
+
+use crate::address::{encode_from_pkscript,PktAddress};
+use crate::backend::{self,ChainBackend,TxStatus};
+use crate::batch;
+use crate::keystore;
+use crate::multisig;
+use crate::network;
+use crate::seed;
+use crate::vote::Vote;
+use crate::wallet::{self,amount_pkt};
+
+const USAGE: &str = "Usage: PKT-Voter <command> [options]
+
+Commands:
+  address <KEY|DESCRIPTOR>      Print the addresses belonging to a private key, segwit and legacy,
+                                or the address of an output descriptor
+  balance <ADDRESS|KEY|DESCRIPTOR>
+                                Print the balance of an address, or of all addresses of a key
+  build-vote <KEY|ADDRESS|DESCRIPTOR> <VOTE>
+                                Build a vote and print it as a base64 PSBT, if an address or
+                                descriptor is given instead of a key, the PSBT is left for an
+                                offline signer or for the cosigners
+  sign <KEY> <PSBT>             Sign a PSBT (base64 or file) and print the raw transaction hex,
+                                this makes no network requests so it can be used offline, if it
+                                still needs the signatures of other cosigners then the signed PSBT
+                                is printed instead, pass it on to the next cosigner
+  combine <PSBT> <PSBT>...      Merge the signatures of PSBTs of the same vote signed by different
+                                cosigners, print the raw transaction hex if it has enough of them,
+                                otherwise print the merged PSBT and which signatures are missing
+  broadcast <TX>                Show and broadcast a signed transaction (hex or file), this can
+                                also be used to re-send a vote which was dropped from the mempool
+  vote <KEY> <VOTE>             Build, sign and broadcast a vote in one step
+  batch-vote <KEYS> <VOTE>      Vote from many addresses, KEYS is a file with one private key per
+                                line, or - to read them from stdin
+  bump-fee <KEY> <TX>           Replace a stuck vote (txid, hex or file) with one paying a higher
+                                fee, use --fee-rate to choose how much
+  cpfp <KEY> <TX>               Speed up an unconfirmed transaction (txid, hex or file) which
+                                cannot be replaced by spending its change with a high enough fee
+  scan-seed                     List the funded addresses of a BIP39 or pktwallet seed
+  seed-key <PATH>               Print the private key at PATH (e.g. m/84'/390'/0'/0/3) of a seed
+                                phrase, to vote with it use seed:<PATH> as the <KEY> instead so
+                                that the PSBT records which key of the seed it is
+  keystore-add <KEY>            Save a private key in the encrypted keystore
+  keystore-list                 Print the addresses of the keys in the keystore
+  status <TX>                   Print whether a transaction (txid, hex or file) is in the mempool
+                                or how many confirmations it has
+  tip                           Print the height and hash of the best block
+  help                          Show this message
+
+<KEY> is a WIF private key, use - to read it from stdin so that it does not appear in
+your shell history or the process list, keystore:<ADDRESS> to use a key from the keystore, or
+seed:<PATH> to use the key at PATH (e.g. m/84'/390'/0'/0/3) of a seed phrase read from stdin.
+A key can spend from both its segwit (pkt1...) and its legacy (p...) address, votes are made
+from whichever of them has the most coins.
+The keystore is ~/.pkt-voter-keys unless PKT_VOTER_KEYSTORE is set, its password is read from
+stdin.
+
+<DESCRIPTOR> is a segwit output descriptor of a multisig or other script, for example
+wsh(multi(2,<PUBKEY1>,<PUBKEY2>,<PUBKEY3>)), use --index <N> to choose the address of a
+descriptor which has a wildcard (default 0).
+
+<VOTE> is one of:
+  --for <ADDRESS>               Vote for this address
+  --nobody                      Vote for nobody
+and optionally:
+  --candidate                   Declare yourself a candidate
+  --consolidate                 Spend every coin of the address into the change of the vote
+  --fee-rate <UNITS>            Fee rate in units per vbyte, by default it is estimated by the
+                                backend if possible, otherwise 4
+
+The seed commands read the seed words from stdin, with --passphrase the next line is the seed
+passphrase. scan-seed scans the account --path <PATH> (default m/84'/390'/0', or m/84'/0'/0' for
+a pktwallet seed).
+
+Other options:
+  --out <FILE>                  Write the result to FILE rather than stdout
+  --yes                         Do not ask for confirmation before broadcasting, the question is
+                                asked on the terminal so stdin can be used for the keys
+  --backend <SPEC>              Where to get chain data and send transactions, see below,
+                                this can also be set with the PKT_VOTER_BACKEND variable
+  --network <NAME>              mainnet (default), testnet or regtest, this can also be set with
+                                the PKT_VOTER_NETWORK variable
+";
+
+/// Flags which take a value.
+const VALUE_FLAGS: &[&str] = &["--for", "--out", "--backend", "--fee-rate", "--path", "--index", "--network"];
+
+/// Flags which take no value, any other flag is refused so that a mistyped one does not
+/// silently change the vote.
+const SWITCHES: &[&str] = &["--candidate", "--nobody", "--consolidate", "--yes", "--passphrase", "--help"];
+
+struct Args {
+    pos: Vec<String>,
+    flags: BTreeMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut out = Args{ pos: Vec::new(), flags: BTreeMap::new() };
+        let mut it = args.iter();
+        while let Some(a) = it.next() {
+            if !a.starts_with("--") {
+                out.pos.push(a.clone());
+            } else if VALUE_FLAGS.contains(&a.as_str()) {
+                let Some(v) = it.next() else {
+                    bail!("Flag {a} requires a value");
+                };
+                out.flags.insert(a.clone(), v.clone());
+            } else if SWITCHES.contains(&a.as_str()) {
+                out.flags.insert(a.clone(), String::new());
+            } else {
+                bail!("Unknown option: {a}\n\n{USAGE}");
+            }
+        }
+        Ok(out)
+    }
+    fn pos(&self, n: usize, what: &str) -> Result<&str> {
+        match self.pos.get(n) {
+            Some(s) => Ok(s),
+            None => bail!("Missing argument: {what}\n\n{USAGE}"),
+        }
+    }
+    fn flag(&self, name: &str) -> Option<&str> {
+        self.flags.get(name).map(|s|s.as_str())
+    }
+    fn has(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+}
+
+/// Read a private key argument, - means read a line from stdin
+/// and keystore:<ADDRESS> means take it from the keystore.
+fn read_key(arg: &str) -> Result<String> {
+    if let Some(addr) = arg.strip_prefix("keystore:") {
+        let secp = Secp256k1::new();
+        for wif in unlock_keystore()? {
+            if wallet::wif_key_to_addresses(&wif, &secp)?.iter().any(|ai|ai.addr_str == addr) {
+                return Ok(wif);
+            }
+        }
+        bail!("There is no key for {addr} in the keystore");
+    }
+    if arg != "-" {
+        return Ok(arg.into());
+    }
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().into())
+}
+
+fn unlock_keystore() -> Result<Vec<String>> {
+    let path = keystore::default_path();
+    if !path.exists() {
+        bail!("There is no keystore at {}", path.display());
+    }
+    eprint!("Keystore password: ");
+    keystore::load(&path, &read_key("-")?)
+}
+
+/// The addresses of a private key argument. seed:<PATH> means the key at PATH of the seed which
+/// is read from stdin, its BIP32 origin is kept so that PSBTs record where the key comes from.
+fn read_key_addresses(args: &Args, arg: &str, secp: &Secp256k1<All>) -> Result<Vec<wallet::AddrInfo>> {
+    let Some(path) = arg.strip_prefix("seed:") else {
+        return wallet::wif_key_to_addresses(&read_key(arg)?, secp);
+    };
+    let (words, passphrase) = read_seed(args)?;
+    let (key, origin) = seed::account_key(&words, &passphrase, Some(path), secp)?;
+    Ok(seed::key_addresses(&key, origin, secp))
+}
+
+/// Accept either a private key, giving all of its addresses, or an address or descriptor,
+/// giving a watch-only AddrInfo.
+fn read_key_or_address(args: &Args, arg: &str, secp: &Secp256k1<All>) -> Result<Vec<wallet::AddrInfo>> {
+    if arg.starts_with("seed:") {
+        return read_key_addresses(args, arg, secp);
+    }
+    let arg = read_key(arg)?;
+    if multisig::is_descriptor(&arg) {
+        return Ok(vec![multisig::descriptor_address(&arg, descriptor_index(args)?)?]);
+    }
+    match wallet::wif_key_to_addresses(&arg, secp) {
+        Err(e) if wallet::script_from_address(&arg).is_err() => Err(e),
+        Ok(addrs) => Ok(addrs),
+        Err(_) => Ok(vec![wallet::watch_only_address(&arg)?]),
+    }
+}
+
+/// Of the addresses of a key, the one which scr pays to, or the first if none of them.
+fn address_for_script(mut addrs: Vec<wallet::AddrInfo>, scr: Option<&ScriptBuf>) -> wallet::AddrInfo {
+    let i = addrs.iter().position(|ai|Some(&ai.change_script) == scr).unwrap_or(0);
+    addrs.swap_remove(i)
+}
+
+fn descriptor_index(args: &Args) -> Result<u32> {
+    match args.flag("--index").map(|i|i.parse::<u32>()) {
+        Some(Ok(index)) => Ok(index),
+        Some(Err(_)) => bail!("--index must be a number"),
+        None => Ok(0),
+    }
+}
+
+/// Read the seed words, and the passphrase if --passphrase is given, from stdin.
+fn read_seed(args: &Args) -> Result<(String, String)> {
+    let words = read_key("-")?;
+    let passphrase = if args.has("--passphrase") { read_key("-")? } else { String::new() };
+    Ok((words, passphrase))
+}
+
+fn write_output(args: &Args, data: &str) -> Result<()> {
+    if let Some(file) = args.flag("--out") {
+        std::fs::write(file, format!("{data}\n"))?;
+        eprintln!("Wrote {file}");
+    } else {
+        println!("{data}");
+    }
+    Ok(())
+}
+
+fn vote_choice(args: &Args) -> Result<Vote> {
+    let is_candidate = args.has("--candidate");
+    match (args.flag("--for"), args.has("--nobody")) {
+        (Some(_), true) => bail!("--for and --nobody cannot be used together"),
+        (Some(addr), false) => match PktAddress::parse(addr) {
+            Ok(addr) => Ok(Vote{ vote_for: Some(addr.script_pubkey()), is_candidate }),
+            Err(e) if e.positions.is_empty() => bail!("Unable to handle vote for address: {e}"),
+            Err(e) => {
+                let marks: String = (0..=*e.positions.iter().max().unwrap())
+                    .map(|i|if e.positions.contains(&i) { '^' } else { ' ' }).collect();
+                bail!("Unable to handle vote for address: {e}\n  {}\n  {marks}", addr.trim());
+            }
+        },
+        (None, true) => Ok(Vote{ vote_for: None, is_candidate }),
+        (None, false) => bail!("Please specify --for <ADDRESS> or --nobody\n\n{USAGE}"),
+    }
+}
+
+fn print_txn(v: &psbt::Psbt) {
+    print_tx_parts(&wallet::psbt_prevouts(v), &v.unsigned_tx.output, wallet::estimate_vsize(v));
+}
+
+fn print_tx_parts(prevouts: &[(OutPoint, Option<TxOut>)], outputs: &[TxOut], vsize: u64) {
+    let mut fees = Some(Amount::ZERO);
+    eprintln!("Inputs:");
+    for (op, prevout) in prevouts {
+        if let Some(wutxo) = prevout {
+            let (address, _) = encode_from_pkscript(&wutxo.script_pubkey);
+            fees = fees.map(|f|f + wutxo.value);
+            eprintln!("  {}  {}  {}", op, address, amount_pkt(&wutxo.value));
+        } else {
+            fees = None;
+            eprintln!("  {}  (unknown amount)", op);
+        }
+    }
+    eprintln!("Outputs:");
+    for output in outputs.iter() {
+        let (address, _) = encode_from_pkscript(&output.script_pubkey);
+        fees = fees.map(|f|f.checked_sub(output.value).unwrap_or(Amount::ZERO));
+        eprintln!("  {}  {}", address, amount_pkt(&output.value));
+    }
+    match fees {
+        Some(fees) => eprintln!("Fees: {}  ({vsize} vbytes, {:.2} units per vbyte)",
+            amount_pkt(&fees), fees.to_sat() as f64 / vsize as f64),
+        None => eprintln!("Fees: unknown  ({vsize} vbytes)"),
+    }
+}
+
+/// The terminal, which is where the answer to a question comes from since stdin may be a pipe
+/// carrying the keys.
+#[cfg(windows)]
+const TERMINAL: &str = "CONIN$";
+#[cfg(not(windows))]
+const TERMINAL: &str = "/dev/tty";
+
+fn confirm(args: &Args, question: &str) -> Result<bool> {
+    if args.has("--yes") {
+        return Ok(true);
+    }
+    let terminal = match std::fs::File::open(TERMINAL) {
+        Ok(t) => t,
+        Err(e) => bail!("Unable to ask for confirmation on the terminal ({e}), use --yes to send without asking"),
+    };
+    eprint!("{question} [y/N] ");
+    let mut line = String::new();
+    std::io::BufReader::new(terminal).read_line(&mut line)?;
+    Ok(matches!(line.trim(), "y" | "Y" | "yes"))
+}
+
+async fn build_vote(
+    args: &Args,
+    backend: &dyn ChainBackend,
+    ai: &wallet::AddrInfo,
+) -> Result<psbt::Psbt> {
+    let vote = vote_choice(args)?;
+    let balance = backend.get_balance(&ai.addr_str).await?;
+    if balance == Amount::ZERO {
+        bail!("Cannot vote with zero balance.");
+    }
+    let fee_rate = wallet::choose_fee_rate(backend, fee_rate_flag(args)?).await;
+    let utxos = wallet::get_address_utxos(backend, &ai.addr_str).await?;
+    let mut psbt = wallet::make_vote(ai, &utxos, args.has("--consolidate"), fee_rate, &vote)?;
+    wallet::add_non_witness_utxos(backend, &mut psbt).await?;
+    Ok(psbt)
+}
+
+/// Output the transaction if the PSBT has all of its signatures, otherwise output the PSBT
+/// so that it can be passed on to the next cosigner.
+fn finish_psbt(args: &Args, psbt: psbt::Psbt, secp: &Secp256k1<All>) -> Result<()> {
+    match multisig::describe_missing(&multisig::signature_status(&psbt)) {
+        Some(missing) => {
+            eprintln!("Not complete yet, pass this PSBT on to the next cosigner:\n{missing}");
+            write_output(args, &wallet::encode_psbt(&psbt))
+        }
+        None => {
+            let tx = wallet::finalize_psbt(psbt, secp)?;
+            write_output(args, &encode::serialize_hex(&tx))
+        }
+    }
+}
+
+fn fee_rate_flag(args: &Args) -> Result<Option<FeeRate>> {
+    match args.flag("--fee-rate") {
+        Some(rate) => Ok(Some(wallet::parse_fee_rate(rate)?)),
+        None => Ok(None),
+    }
+}
+
+async fn bcast(backend: &dyn ChainBackend, txn: &bitcoin::Transaction) -> Result<()> {
+    backend.bcast_transaction(txn).await?;
+    println!("{}", txn.txid());
+    eprintln!("Transaction sent successfully");
+    Ok(())
+}
+
+/// The backend chosen by --backend or the environment, only made by the commands which need one.
+fn chain_backend(args: &Args) -> Result<Arc<dyn ChainBackend>> {
+    match args.flag("--backend") {
+        Some(spec) => backend::from_spec(spec),
+        None => backend::from_env(),
+    }
+}
+
+async fn run(args: Args) -> Result<()> {
+    let secp = Secp256k1::new();
+    let cmd = if args.has("--help") { "help" } else { args.pos(0, "command")? };
+    // Before anything which might fail because of a bad option or environment variable
+    if cmd == "help" || cmd == "-h" {
+        print!("{USAGE}");
+        println!("\nBackends:\n{}", backend::BACKEND_HELP);
+        return Ok(());
+    }
+    network::init(args.flag("--network"))?;
+    match cmd {
+        "address" => {
+            for ai in read_key_or_address(&args, args.pos(1, "KEY|DESCRIPTOR")?, &secp)? {
+                println!("{}", ai.addr_str);
+            }
+        }
+        "balance" => {
+            let backend = &*chain_backend(&args)?;
+            let mut balance = Amount::ZERO;
+            for ai in read_key_or_address(&args, args.pos(1, "ADDRESS|KEY|DESCRIPTOR")?, &secp)? {
+                balance += backend.get_balance(&ai.addr_str).await?;
+            }
+            println!("{}", amount_pkt(&balance));
+        }
+        "build-vote" => {
+            let backend = &*chain_backend(&args)?;
+            let addrs = read_key_or_address(&args, args.pos(1, "KEY|ADDRESS|DESCRIPTOR")?, &secp)?;
+            let ai = wallet::choose_funded(backend, addrs).await?;
+            let psbt = build_vote(&args, backend, &ai).await?;
+            print_txn(&psbt);
+            write_output(&args, &wallet::encode_psbt(&psbt))?;
+        }
+        "sign" => {
+            let ai = read_key_addresses(&args, args.pos(1, "KEY")?, &secp)?.remove(0);
+            let psbt = wallet::load_psbt(args.pos(2, "PSBT")?)?;
+            print_txn(&psbt);
+            let psbt = wallet::sign_psbt(&ai, psbt, &secp)?;
+            finish_psbt(&args, psbt, &secp)?;
+        }
+        "combine" => {
+            let mut psbts = Vec::new();
+            for arg in args.pos.iter().skip(1) {
+                psbts.push(wallet::load_psbt(arg)?);
+            }
+            if psbts.len() < 2 {
+                bail!("Missing argument: at least two PSBTs to combine\n\n{USAGE}");
+            }
+            let psbt = multisig::combine(psbts)?;
+            print_txn(&psbt);
+            finish_psbt(&args, psbt, &secp)?;
+        }
+        "broadcast" => {
+            let backend = &*chain_backend(&args)?;
+            let tx = wallet::load_raw_txn(args.pos(1, "TX")?)?;
+            eprintln!("Transaction: {}", tx.txid());
+            print_tx_parts(&wallet::fetch_prevouts(backend, &tx).await, &tx.output, tx.vsize() as u64);
+            if !confirm(&args, "Send this transaction?")? {
+                bail!("Cancelled");
+            }
+            bcast(backend, &tx).await?;
+        }
+        "vote" => {
+            let backend = &*chain_backend(&args)?;
+            let addrs = read_key_addresses(&args, args.pos(1, "KEY")?, &secp)?;
+            let ai = wallet::choose_funded(backend, addrs).await?;
+            eprintln!("Address: {}", ai.addr_str);
+            let psbt = build_vote(&args, backend, &ai).await?;
+            print_txn(&psbt);
+            if !confirm(&args, "Send this transaction?")? {
+                bail!("Cancelled");
+            }
+            let tx = wallet::sign_txn(&ai, psbt, &secp)?;
+            bcast(backend, &tx).await?;
+        }
+        "batch-vote" => {
+            let backend = &*chain_backend(&args)?;
+            let keys = match args.pos(1, "KEYS")? {
+                "-" => std::io::read_to_string(std::io::stdin())?,
+                file => std::fs::read_to_string(file)?,
+            };
+            let keys = batch::funded(backend, batch::parse_keys(&keys, &secp)?).await;
+            let vote = vote_choice(&args)?;
+            let fee_rate = wallet::choose_fee_rate(backend, fee_rate_flag(&args)?).await;
+            let consolidate = args.has("--consolidate");
+            let mut votes = Vec::new();
+            for ai in &keys {
+                match batch::prepare(backend, ai, consolidate, fee_rate, &vote).await {
+                    Ok(psbt) => {
+                        eprintln!("Vote from {}:", ai.addr_str);
+                        print_txn(&psbt);
+                        votes.push((ai, psbt));
+                    }
+                    Err(e) => eprintln!("Skipping {}: {e}", ai.addr_str),
+                }
+            }
+            if votes.is_empty() {
+                bail!("None of the addresses is able to vote");
+            }
+            if !confirm(&args, &format!("Send {} votes?", votes.len()))? {
+                bail!("Cancelled");
+            }
+            let mut failed = 0;
+            for (ai, psbt) in votes {
+                match batch::send(backend, ai, psbt, &secp).await {
+                    Ok(tx) => println!("{} {}", ai.addr_str, tx.txid()),
+                    Err(e) => {
+                        failed += 1;
+                        println!("{} error: {e}", ai.addr_str);
+                    }
+                }
+            }
+            if failed > 0 {
+                bail!("{failed} votes failed");
+            }
+        }
+        "bump-fee" => {
+            let backend = &*chain_backend(&args)?;
+            let addrs = read_key_addresses(&args, args.pos(1, "KEY")?, &secp)?;
+            let original = wallet::load_txn(backend, args.pos(2, "TX")?).await?;
+            let prevouts = wallet::fetch_prevouts(backend, &original).await;
+            let ai = address_for_script(addrs, prevouts.iter().find_map(|(_, p)|p.as_ref()).map(|p|&p.script_pubkey));
+            let fee_rate = wallet::choose_fee_rate(backend, fee_rate_flag(&args)?).await;
+            let mut psbt = wallet::bump_vote(&ai, &original, &prevouts, fee_rate)?;
+            wallet::add_non_witness_utxos(backend, &mut psbt).await?;
+            eprintln!("Replaces: {}", original.txid());
+            print_txn(&psbt);
+            if !confirm(&args, "Send this transaction?")? {
+                bail!("Cancelled");
+            }
+            let tx = wallet::sign_txn(&ai, psbt, &secp)?;
+            bcast(backend, &tx).await?;
+        }
+        "cpfp" => {
+            let backend = &*chain_backend(&args)?;
+            let addrs = read_key_addresses(&args, args.pos(1, "KEY")?, &secp)?;
+            let parent = wallet::load_txn(backend, args.pos(2, "TX")?).await?;
+            let paid = parent.output.iter()
+                .find(|txout|addrs.iter().any(|ai|ai.change_script == txout.script_pubkey))
+                .map(|txout|&txout.script_pubkey);
+            let ai = address_for_script(addrs.clone(), paid);
+            let prevouts = wallet::fetch_prevouts(backend, &parent).await;
+            let unspent = backend.get_utxos(&ai.addr_str, true).await?;
+            let fee_rate = wallet::choose_fee_rate(backend, fee_rate_flag(&args)?).await;
+            let mut psbt = wallet::child_pays_for_parent(&ai, &parent, &prevouts, &unspent, fee_rate)?;
+            wallet::add_non_witness_utxos(backend, &mut psbt).await?;
+            eprintln!("Pays for: {}", parent.txid());
+            print_txn(&psbt);
+            if !confirm(&args, "Send this transaction?")? {
+                bail!("Cancelled");
+            }
+            let tx = wallet::sign_txn(&ai, psbt, &secp)?;
+            bcast(backend, &tx).await?;
+        }
+        "scan-seed" => {
+            let backend = &*chain_backend(&args)?;
+            let (words, passphrase) = read_seed(&args)?;
+            let (account, origin) = seed::account_key(&words, &passphrase, args.flag("--path"), &secp)?;
+            for sa in seed::discover(backend, &account, &origin, &secp).await? {
+                println!("{} {} {}", sa.path, sa.ai.addr_str, amount_pkt(&sa.balance));
+            }
+        }
+        "seed-key" => {
+            let path = args.pos(1, "PATH")?;
+            let (words, passphrase) = read_seed(&args)?;
+            let (key, _) = seed::account_key(&words, &passphrase, Some(path), &secp)?;
+            println!("{}", wallet::encode_wif(&key.to_priv()));
+        }
+        "keystore-add" => {
+            let wif = read_key(args.pos(1, "KEY")?)?;
+            let ai = wallet::wif_key_to_address(&wif, &secp)?;
+            let path = keystore::default_path();
+            eprint!("Keystore password: ");
+            keystore::add(&path, &read_key("-")?, &wif)?;
+            eprintln!("Saved the key of {} in {}", ai.addr_str, path.display());
+        }
+        "keystore-list" => {
+            for wif in unlock_keystore()? {
+                let addrs: Vec<_> = wallet::wif_key_to_addresses(&wif, &secp)?.into_iter().map(|ai|ai.addr_str).collect();
+                println!("{}", addrs.join(" "));
+            }
+        }
+        "status" => {
+            let backend = &*chain_backend(&args)?;
+            let tx = wallet::load_txn(backend, args.pos(1, "TX")?).await?;
+            match backend.get_tx_status(&tx.txid()).await? {
+                TxStatus::NotFound if wallet::inputs_unspent(backend, &tx).await? => {
+                    println!("not found");
+                }
+                TxStatus::NotFound => println!("double-spent"),
+                TxStatus::Mempool => println!("mempool"),
+                TxStatus::Confirmed{ height, confirmations } => {
+                    println!("confirmed {height} {confirmations}");
+                }
+            }
+        }
+        "tip" => {
+            let backend = &*chain_backend(&args)?;
+            eprintln!("Backend: {}", backend.name());
+            let tip = backend.get_tip().await?;
+            println!("{} {}", tip.height, tip.hash);
+        }
+        _ => {
+            bail!("Unknown command: {cmd}\n\n{USAGE}");
+        }
+    }
+    Ok(())
+}
+
+pub fn main(args: &[String]) -> Result<()> {
+    let args = Args::parse(args)?;
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(run(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(&args.iter().map(|a|a.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn known_flags() {
+        let args = parse(&["vote", "-", "--for", "pkt1q", "--candidate", "--fee-rate", "5"]).unwrap();
+        assert_eq!(args.pos, vec!["vote", "-"]);
+        assert_eq!(args.flag("--for"), Some("pkt1q"));
+        assert_eq!(args.flag("--fee-rate"), Some("5"));
+        assert!(args.has("--candidate"));
+        assert!(parse(&["vote", "-", "--for"]).is_err());
+    }
+
+    #[test]
+    fn unknown_flags() {
+        let err = parse(&["vote", "-", "--for", "pkt1q", "--candiate"]).err().unwrap().to_string();
+        assert!(err.starts_with("Unknown option: --candiate"), "{err}");
+        assert!(parse(&["vote", "-", "--nobody", "--fee-rat", "50"]).is_err());
+    }
+}
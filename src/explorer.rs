@@ -1,9 +1,16 @@
-use std::str::FromStr;
-
+use async_trait::async_trait;
 use bitcoin::consensus;
+use bitcoin::Amount;
+use bitcoin::OutPoint;
+use bitcoin::TxOut;
+use bitcoin::Txid;
 use anyhow::{bail,Result};
 use serde::{Serialize,Deserialize};
 
+use crate::backend::{ChainBackend,TxStatus};
+use crate::network;
+use crate::types::RpcBestBlock;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct TransactionInput {
     pub address: String,
@@ -48,95 +55,197 @@ pub struct Transaction {
 #[derive(Debug, Deserialize, Serialize)]
 struct PagedData<T> {
     pub results: Vec<T>,
+    #[serde(default)]
     pub prev: String,
+    #[serde(default)]
     pub next: String,
 }
 
-pub async fn get_transaction_bin(txid: &str) -> Result<bitcoin::Transaction> {
-    let bs = reqwest::get(&format!("https://explorer.cjdns.fr/api/v2/tx/{txid}/bin"))
-        .await?
-        .bytes()
-        .await?;
-    let txn = consensus::deserialize(&bs[..])?;
-    Ok(txn)
+/// The public block explorers, explorer.pkt.cash for address info
+/// and explorer.cjdns.fr for transactions.
+pub struct Explorer {
+    pub addr_api: String,
+    pub tx_api: String,
+}
+
+impl Default for Explorer {
+    /// The public explorers of the current network, empty if it has none
+    fn default() -> Self {
+        let (addr_api, tx_api) = network::current().explorer.unwrap_or_default();
+        Explorer{
+            addr_api: addr_api.into(),
+            tx_api: tx_api.into(),
+        }
+    }
 }
 
-pub async fn get_transaction(addr: &str) -> Result<bitcoin::Transaction> {
-    // This will not spend mined coins, which makes life easier because we don't have to care
-    // if they are not yet mature.
-    let mut url = format!("https://explorer.pkt.cash/api/v1/PKT/pkt/address/{addr}/coins");
-    loop {
-        println!("Request: {url}");
-        let bs = reqwest::get(&url)
+impl Explorer {
+    async fn get_transaction_bin(&self, txid: &str) -> Result<bitcoin::Transaction> {
+        let bs = reqwest::get(&format!("{}/tx/{txid}/bin", self.tx_api))
             .await?
-            .text()
+            .bytes()
             .await?;
-        let txns: PagedData<Transaction> = serde_json::from_str(&bs)?;
-        for txn in txns.results {
-            if txn.block_hash.is_empty() {
-                // Skip unconfirmed
-                continue;
-            }
-            for out in txn.output.into_iter() {
-                let value = u64::from_str(&out.value)?;
-                if out.address != addr {
-                    // Paying someone else
+        let txn = consensus::deserialize(&bs[..])?;
+        Ok(txn)
+    }
+
+    /// Every transaction which has at least one unspent output paying to addr, along with the
+    /// indexes of those outputs. Transactions which are not yet in a block are skipped unless
+    /// include_unconfirmed is set.
+    async fn get_unspent_outputs(
+        &self,
+        addr: &str,
+        include_unconfirmed: bool,
+    ) -> Result<Vec<(String, Vec<u32>)>> {
+        // This will not spend mined coins, which makes life easier because we don't have to care
+        // if they are not yet mature.
+        let mut url = format!("{}/address/{addr}/coins", self.addr_api);
+        let mut out: Vec<(String, Vec<u32>)> = Vec::new();
+        loop {
+            eprintln!("Request: {url}");
+            let bs = reqwest::get(&url)
+                .await?
+                .text()
+                .await?;
+            let txns: PagedData<Transaction> = serde_json::from_str(&bs)?;
+            for txn in txns.results {
+                if txn.block_hash.is_empty() && !include_unconfirmed {
+                    // Skip unconfirmed
                     continue;
                 }
-                if out.spentcount != 0 {
-                    // Already spent
+                if out.iter().any(|(txid, _)|*txid == txn.txid) {
                     continue;
                 }
-                if value < 500 {
-                    // Unable to pay the fee
-                    continue;
+                let mut unspent = Vec::new();
+                for (n, output) in txn.output.into_iter().enumerate() {
+                    if output.address != addr {
+                        // Paying someone else
+                        continue;
+                    }
+                    if output.spentcount != 0 {
+                        // Already spent
+                        continue;
+                    }
+                    unspent.push(n as u32);
+                }
+                if !unspent.is_empty() {
+                    out.push((txn.txid, unspent));
                 }
-                return get_transaction_bin(&txn.txid).await;
             }
-        }
-        url = txns.next;
-        if url.is_empty() {
-            bail!("No usable transactions found for address");
+            url = txns.next;
+            if url.is_empty() {
+                return Ok(out);
+            }
         }
     }
 }
 
-pub async fn get_balance(addr: &str) -> Result<f64> {
-    #[derive(Deserialize,Debug)]
-    struct Balance {
-        pub balance: String,
+#[async_trait]
+impl ChainBackend for Explorer {
+    fn name(&self) -> String {
+        format!("explorer:{},{}", self.addr_api, self.tx_api)
     }
-    println!("Make request");
-    let bs = reqwest::get(
-        format!("https://explorer.pkt.cash/api/v1/PKT/pkt/address/{addr}/balance"),
-    )
-        .await?
-        .text()
-        .await?;
-    let bal: Balance = serde_json::from_str(&bs)?;
-    println!("Got balance: {bal:?}");
-    let balance = u64::from_str_radix(&bal.balance, 10)?;
-    Ok((balance as f64) / 1073741824.0)
-}
 
-pub async fn bcast_transaction(txn: &bitcoin::Transaction) -> Result<()> {
-    let real_txid = txn.txid().to_string();
-    let txn_bytes = consensus::encode::serialize(txn);
-    let bs = reqwest::Client::new()
-        .post("https://explorer.cjdns.fr/api/v2/tx/bcast-bin")
-        .body(reqwest::Body::from(txn_bytes))
-        .send()
-        .await?
-        .text()
-        .await?;
-    let txid: String = match serde_json::from_str(&bs) {
-        Ok(s) => s,
-        Err(e) => {
-            bail!("Error sending transaction: {e}");
+    async fn get_utxos(&self, addr: &str, include_unconfirmed: bool) -> Result<Vec<(OutPoint,TxOut)>> {
+        let script = crate::wallet::script_from_address(addr)?;
+        let mut out = Vec::new();
+        for (txid, unspent) in self.get_unspent_outputs(addr, include_unconfirmed).await? {
+            let txn = self.get_transaction_bin(&txid).await?;
+            for n in unspent {
+                // The explorer lists the outputs in order, double check that it is the right one.
+                match txn.output.get(n as usize) {
+                    Some(txout) if txout.script_pubkey == script => {
+                        out.push((
+                            OutPoint{
+                                txid: txn.txid(),
+                                vout: n,
+                            },
+                            txout.clone(),
+                        ));
+                    }
+                    _ => {
+                        eprintln!("Output {txid}:{n} does not pay to {addr}, skipping");
+                    }
+                }
+            }
         }
-    };
-    if txid != real_txid {
-        bail!("Got back txid {txid} but was expecting {real_txid}");
+        Ok(out)
     }
-    Ok(())
-}
\ No newline at end of file
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<bitcoin::Transaction> {
+        self.get_transaction_bin(&txid.to_string()).await
+    }
+
+    async fn get_balance(&self, addr: &str) -> Result<Amount> {
+        #[derive(Deserialize,Debug)]
+        struct Balance {
+            pub balance: String,
+        }
+        eprintln!("Make request");
+        let bs = reqwest::get(
+            format!("{}/address/{addr}/balance", self.addr_api),
+        )
+            .await?
+            .text()
+            .await?;
+        let bal: Balance = serde_json::from_str(&bs)?;
+        eprintln!("Got balance: {bal:?}");
+        let balance = u64::from_str_radix(&bal.balance, 10)?;
+        Ok(Amount::from_sat(balance))
+    }
+
+    async fn get_tx_status(&self, txid: &Txid) -> Result<TxStatus> {
+        let resp = reqwest::get(format!("{}/tx/{txid}", self.addr_api)).await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(TxStatus::NotFound);
+        }
+        let txn: Transaction = serde_json::from_str(&resp.text().await?)?;
+        if txn.block_hash.is_empty() {
+            return Ok(TxStatus::Mempool);
+        }
+        let tip = self.get_tip().await?;
+        Ok(TxStatus::Confirmed{
+            height: txn.block_height,
+            confirmations: (tip.height + 1).saturating_sub(txn.block_height),
+        })
+    }
+
+    async fn bcast_transaction(&self, txn: &bitcoin::Transaction) -> Result<()> {
+        let real_txid = txn.txid().to_string();
+        let txn_bytes = consensus::encode::serialize(txn);
+        let bs = reqwest::Client::new()
+            .post(format!("{}/tx/bcast-bin", self.tx_api))
+            .body(reqwest::Body::from(txn_bytes))
+            .send()
+            .await?
+            .text()
+            .await?;
+        let txid: String = match serde_json::from_str(&bs) {
+            Ok(s) => s,
+            Err(e) => {
+                bail!("Error sending transaction: {e}");
+            }
+        };
+        if txid != real_txid {
+            bail!("Got back txid {txid} but was expecting {real_txid}");
+        }
+        Ok(())
+    }
+
+    async fn get_tip(&self) -> Result<RpcBestBlock> {
+        #[derive(Deserialize,Debug)]
+        struct ChainBlock {
+            pub hash: String,
+            pub height: u32,
+        }
+        let bs = reqwest::get(format!("{}/chain/down/1/1", self.addr_api))
+            .await?
+            .text()
+            .await?;
+        let blocks: PagedData<ChainBlock> = serde_json::from_str(&bs)?;
+        let Some(tip) = blocks.results.into_iter().next() else {
+            bail!("Explorer returned no blocks");
+        };
+        Ok(RpcBestBlock{ hash: tip.hash, height: tip.height })
+    }
+}
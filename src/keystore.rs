@@ -0,0 +1,187 @@
+use std::io::Write;
+use std::path::{Path,PathBuf};
+
+use argon2::{Algorithm,Argon2,Params,Version};
+use chacha20poly1305::aead::{Aead,AeadCore,KeyInit,OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{Key,XChaCha20Poly1305,XNonce};
+use anyhow::{anyhow,bail,Result};
+use serde::{Serialize,Deserialize};
+
+/// Environment variable which sets where the keystore is kept
+pub const KEYSTORE_ENV: &str = "PKT_VOTER_KEYSTORE";
+
+const KEYSTORE_VERSION: u32 = 1;
+
+/// The keystore file, private keys are encrypted with XChaCha20-Poly1305 using a key derived
+/// from the password with Argon2id.
+#[derive(Debug, Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u32,
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Where the keystore is, either set by PKT_VOTER_KEYSTORE or .pkt-voter-keys in the home directory.
+pub fn default_path() -> PathBuf {
+    if let Ok(path) = std::env::var(KEYSTORE_ENV) {
+        if !path.is_empty() {
+            return path.into();
+        }
+    }
+    let home = std::env::var("HOME").or_else(|_|std::env::var("USERPROFILE")).unwrap_or_default();
+    Path::new(&home).join(".pkt-voter-keys")
+}
+
+fn derive_key(password: &str, salt: &[u8], params: Params) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e|anyhow!("Unable to derive key from password: {e}"))?;
+    Ok(key)
+}
+
+/// Decrypt the WIF keys in the keystore.
+pub fn load(path: &Path, password: &str) -> Result<Vec<String>> {
+    let file: KeystoreFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    if file.version != KEYSTORE_VERSION {
+        bail!("Keystore {} has unknown version {}", path.display(), file.version);
+    }
+    let params = match Params::new(file.m_cost, file.t_cost, file.p_cost, None) {
+        Ok(params) => params,
+        Err(e) => bail!("Keystore {} has invalid parameters: {e}", path.display()),
+    };
+    let key = derive_key(password, &hex::decode(&file.salt)?, params)?;
+    let nonce = hex::decode(&file.nonce)?;
+    if nonce.len() != 24 {
+        bail!("Keystore {} has an invalid nonce", path.display());
+    }
+    let plain = XChaCha20Poly1305::new(&key)
+        .decrypt(XNonce::from_slice(&nonce), &hex::decode(&file.ciphertext)?[..])
+        .map_err(|_|anyhow!("Wrong password for keystore {}", path.display()))?;
+    Ok(serde_json::from_slice(&plain)?)
+}
+
+/// Encrypt the WIF keys with the password and write them to the keystore.
+pub fn save(path: &Path, password: &str, keys: &[String]) -> Result<()> {
+    if password.is_empty() {
+        bail!("Please choose a password for the keystore");
+    }
+    let params = Params::DEFAULT;
+    let mut salt = [0_u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(password, &salt, params.clone())?;
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = XChaCha20Poly1305::new(&key)
+        .encrypt(&nonce, &serde_json::to_vec(keys)?[..])
+        .map_err(|_|anyhow!("Unable to encrypt keystore"))?;
+    let file = KeystoreFile{
+        version: KEYSTORE_VERSION,
+        m_cost: params.m_cost(),
+        t_cost: params.t_cost(),
+        p_cost: params.p_cost(),
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    };
+    // Write to a temporary file first so a crash can't leave us with half a keystore.
+    let tmp = path.with_extension("tmp");
+    // A temporary file left by a crash is not worth keeping
+    let _ = std::fs::remove_file(&tmp);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Only readable by us from the start, before anything is written
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&tmp)?.write_all(serde_json::to_string_pretty(&file)?.as_bytes())?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Add a WIF key to the keystore, creating it if it does not exist.
+pub fn add(path: &Path, password: &str, wif: &str) -> Result<Vec<String>> {
+    let mut keys = if path.exists() { load(path, password)? } else { Vec::new() };
+    if !keys.iter().any(|k|k == wif) {
+        keys.push(wif.to_owned());
+    }
+    save(path, password, &keys)?;
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A keystore path which no other test uses, removed when dropped
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("pkt-voter-test-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            TempPath(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn keys() -> Vec<String> {
+        vec!["key one".into(), "key two".into()]
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = TempPath::new("round-trip");
+        save(&path.0, "password", &keys()).unwrap();
+        assert_eq!(load(&path.0, "password").unwrap(), keys());
+        // Nothing is left in plain text
+        let file = std::fs::read_to_string(&path.0).unwrap();
+        assert!(!file.contains("key one"), "{file}");
+        assert!(save(&path.0, "", &keys()).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path.0).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn wrong_password() {
+        let path = TempPath::new("wrong-password");
+        save(&path.0, "password", &keys()).unwrap();
+        let err = load(&path.0, "passw0rd").unwrap_err().to_string();
+        assert!(err.contains("Wrong password"), "{err}");
+    }
+
+    #[test]
+    fn add_does_not_duplicate() {
+        let path = TempPath::new("add");
+        assert_eq!(add(&path.0, "password", "key one").unwrap(), vec!["key one"]);
+        assert_eq!(add(&path.0, "password", "key two").unwrap(), keys());
+        assert_eq!(add(&path.0, "password", "key one").unwrap(), keys());
+        assert_eq!(load(&path.0, "password").unwrap(), keys());
+    }
+
+    #[test]
+    fn unknown_version() {
+        let path = TempPath::new("version");
+        save(&path.0, "password", &keys()).unwrap();
+        let mut file: KeystoreFile = serde_json::from_str(&std::fs::read_to_string(&path.0).unwrap()).unwrap();
+        file.version = KEYSTORE_VERSION + 1;
+        std::fs::write(&path.0, serde_json::to_string(&file).unwrap()).unwrap();
+        let err = load(&path.0, "password").unwrap_err().to_string();
+        assert!(err.contains("unknown version"), "{err}");
+    }
+}
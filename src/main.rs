@@ -1,206 +1,156 @@
 #![windows_subsystem = "windows"]
-use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::collections::BTreeMap;
 
-use bitcoin::bech32::Hrp;
-use bitcoin::bip32::DerivationPath;
-use bitcoin::bip32::Fingerprint;
 use bitcoin::consensus::encode;
 use bitcoin::key::Secp256k1;
-use bitcoin::key::secp256k1::{self, All};
+use bitcoin::key::secp256k1::All;
 use bitcoin::psbt;
-use bitcoin::psbt::Input;
-use bitcoin::psbt::PsbtSighashType;
-use bitcoin::script::PushBytes;
 use bitcoin::Amount;
+use bitcoin::FeeRate;
 use bitcoin::OutPoint;
-use bitcoin::PubkeyHash;
-use bitcoin::ScriptHash;
-use bitcoin::ScriptBuf;
-use bitcoin::address::Payload;
-use bitcoin::TxIn;
 use bitcoin::TxOut;
-use bitcoin::hashes::Hash;
-use miniscript::psbt::PsbtExt;
+use bitcoin::Txid;
 
 use anyhow::{bail,Result};
 use slint::Weak;
 
+use address::PktAddress;
+use backend::ChainBackend;
+use vote::Vote;
+use wallet::{AddrInfo,Tracked,amount_pkt,get_address_utxos,script_from_address,sign_txn};
+
+mod backend;
 mod explorer;
+mod pktd;
+mod p2p;
 mod address;
 mod packetcrypt;
 mod difficulty;
 mod types;
+mod wallet;
+mod seed;
+mod batch;
+mod keystore;
+mod multisig;
+mod network;
+mod vote;
+mod cli;
 
 slint::include_modules!();
 
-async fn get_usable_utxo(addr: &str) -> Result<(OutPoint,TxOut)> {
-    let script = script_from_address(addr)?;
-    let txn = explorer::get_transaction(addr).await?;
-    for (n, txout) in txn.output.iter().enumerate() {
-        // TODO: If the user has 2 TXOs in the same txn, one spent one unspent, this will
-        //       possibly select the spent one.
-        if txout.script_pubkey == script {
-            return Ok((
-                OutPoint{
-                    txid: txn.txid(),
-                    vout: n as u32,
-                },
-                txout.clone(),
-            ))
-        }
-    }
-    bail!("No usable txout found in selected transaction: {}", txn.txid());
-}
-
-fn amount_pkt(amt: &Amount) -> f64 {
-    amt.to_sat() as f64 / 1073741824.0
-}
-
-fn encode_address_payload(payload: &bitcoin::address::Payload) -> String {
-    bitcoin::address::AddressEncoding{
-        payload: payload,
-        // TODO config
-        p2pkh_prefix: 0x75,
-        p2sh_prefix: 0x38,
-        hrp: Hrp::parse("pkt").unwrap(),
-    }.to_string()
-}
-
-fn script_from_address(addr: &str) -> Result<bitcoin::ScriptBuf> {
-    let prefix = match addr.rfind('1') {
-        None => addr,
-        Some(sep) => addr.split_at(sep).0,
-    };
-    if prefix == "pkt" {
-        let (_hrp, version, data) = bitcoin::bech32::segwit::decode(addr)?;
-        let version = bitcoin::WitnessVersion::try_from(version)?;
-        let program = bitcoin::script::PushBytesBuf::try_from(data)?;
-        let witness_program = bitcoin::WitnessProgram::new(version, program)?;
-        let payload = Payload::WitnessProgram(witness_program);
-        return Ok(payload.script_pubkey())
-    }
-
-    if addr.len() > 50 {
-        bail!("Address is too long to be b58");
-    }
-    let data = bitcoin::base58::decode_check(addr)?;
-    if data.len() != 21 {
-        bail!("Base58 decode of address is not 21 bytes");
-    }
-    if data[0] == 0x75 {
-        // p2pkh
-        Ok(Payload::PubkeyHash(PubkeyHash::from_slice(&data[1..])?).script_pubkey())
-    } else if data[0] == 0x38 {
-        // p2sh
-        Ok(Payload::ScriptHash(ScriptHash::from_slice(&data[1..]).unwrap()).script_pubkey())
-    } else {
-        bail!("First byte of decoded b58 not recognized");
-    }
-}
-
-fn mk_vote_output(vote_for: Option<ScriptBuf>, is_candidate: bool) -> TxOut {
-    let mut vote = if let Some(vf) = vote_for {
-        let bytes = vf.as_bytes();
-        let mut vote = vec![0_u8; bytes.len() + 1];
-        vote[1..].copy_from_slice(bytes);
-        vote
-    } else {
-        vec![0_u8]
-    };
-    vote[0] = if is_candidate { 1 } else { 0 };
-    let pb: &PushBytes = vote[..].try_into().unwrap();
-    TxOut{
-        value: Amount::ZERO,
-        script_pubkey: ScriptBuf::new_op_return(pb),
-    }
+fn describe_txn(v: &psbt::Psbt) -> UiTransaction {
+    describe_tx_parts(&wallet::psbt_prevouts(v), &v.unsigned_tx.output, wallet::estimate_vsize(v))
 }
 
-fn describe_txn(v: &psbt::Psbt) -> UiTransaction {
+fn describe_tx_parts(
+    prevouts: &[(OutPoint, Option<TxOut>)],
+    outputs: &[TxOut],
+    vsize: u64,
+) -> UiTransaction {
     let mut fees = Amount::ZERO;
+    let mut fees_known = true;
     let mut inputs = Vec::new();
-    for (input, txin) in v.inputs.iter().zip(v.unsigned_tx.input.iter()) {
-        if let Some(wutxo) = &input.witness_utxo {
+    for (op, prevout) in prevouts {
+        if let Some(wutxo) = prevout {
             // wutxo.script_pubkey
             let (address, _) = crate::address::encode_from_pkscript(&wutxo.script_pubkey);
             fees += wutxo.value;
             inputs.push(UiTxInput{
                 amount: amount_pkt(&wutxo.value) as f32,
-                prev_n: txin.previous_output.vout as i32,
-                prev_txid: txin.previous_output.txid.to_string().into(),
+                prev_n: op.vout as i32,
+                prev_txid: op.txid.to_string().into(),
                 address: address.into(),
             });
+        } else {
+            fees_known = false;
+            inputs.push(UiTxInput{
+                amount: 0.0,
+                prev_n: op.vout as i32,
+                prev_txid: op.txid.to_string().into(),
+                address: "unknown".into(),
+            });
         }
     }
-    let mut outputs = Vec::new();
-    for output in v.unsigned_tx.output.iter() {
+    let mut outputs_out = Vec::new();
+    for output in outputs.iter() {
         let (address, _) = crate::address::encode_from_pkscript(&output.script_pubkey);
-        fees -= output.value;
-        outputs.push(UiTxOutput{
+        fees = fees.checked_sub(output.value).unwrap_or(Amount::ZERO);
+        outputs_out.push(UiTxOutput{
             amount: amount_pkt(&output.value) as f32,
             address: address.into(),
         })
     }
     UiTransaction{
         inputs: inputs[..].into(),
-        outputs: outputs[..].into(),
-        total_fees: amount_pkt(&fees) as f32,
+        outputs: outputs_out[..].into(),
+        total_fees: if fees_known { amount_pkt(&fees) as f32 } else { -1.0 },
+        vsize: vsize as i32,
+        fee_rate: if fees_known { (fees.to_sat() as f64 / vsize as f64) as f32 } else { -1.0 },
     }
 }
 
-fn sign_txn(ai: &AddrInfo, mut tx: psbt::Psbt, secp: &Secp256k1<All>) -> Result<bitcoin::Transaction> {
-
-    // sign pbst
-    let mut key_map = BTreeMap::new();
-    key_map.insert(ai.public_key, ai.private_key);
-    match tx.sign(&key_map, secp) {
-        Ok(_) => {
-            println!("Signing succeeded")
-        },
-        Err((_, error_map)) => {
-            let mut errors = Vec::new();
-            for (_, e) in error_map {
-                errors.push(format!("Error: {e}"));
-            } 
-            bail!("Failed to sign transaction: {errors:?}");
-        }
-    }
+fn address_label(ai: &AddrInfo) -> String {
+    let watch = if ai.is_watch_only() { " (watch-only)" } else { "" };
+    format!("Address: {}{watch}", ai.addr_str)
+}
 
-    // finalize
-    let tx = match tx.finalize(secp) {
-        Ok(tx) => {
-            println!("Finalizing succeeded");
-            tx
-        }
-        Err((_, errors)) => {
-            bail!("Failed to sign transaction: {errors:?}");
-        }
-    };
+/// The fee rate entered in the form, None if it was left empty.
+fn ui_fee_rate(ui: &SlintApp) -> Result<Option<FeeRate>> {
+    let fee_rate = ui.get_fee_rate();
+    if fee_rate.trim().is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(wallet::parse_fee_rate(&fee_rate)?))
+}
 
-    let tx = tx.extract_tx_unchecked_fee_rate();
-    println!("{}", encode::serialize_hex(&tx));
+/// How often to check on a transaction which was sent
+const TRACK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
-    Ok(tx)
-}
+/// Stop checking on a transaction once it is this deep
+const TRACK_CONFIRMATIONS: u32 = 6;
 
 //////
 
-struct AddrInfo {
-    private_key: bitcoin::PrivateKey,
-    public_key: bitcoin::PublicKey,
-    change_script: bitcoin::address::Payload,
-    addr_str: String,
+/// One address of a batch vote
+struct BatchEntry {
+    ai: AddrInfo,
+    psbt: Option<psbt::Psbt>,
+    row: UiBatchRow,
+}
+
+/// A vote which was broadcast, so that its fee can be bumped
+#[derive(Clone)]
+struct SentVote {
+    txn: bitcoin::Transaction,
+    /// The address which it is paid from
+    ai: AddrInfo,
+    /// If it is a fee bump, the txid of the vote which was first sent
+    original_txid: Option<Txid>,
 }
 
 #[derive(Default)]
 struct AppMut {
     addr_info: Option<AddrInfo>,
-    spend_txout: Option<(bitcoin::OutPoint,TxOut)>,
-    is_candidate: bool,
-    vote_for: Option<ScriptBuf>,
+    utxos: Vec<(bitcoin::OutPoint,TxOut)>,
+    consolidate: bool,
+    fee_rate_estimate: Option<FeeRate>,
+    vote: Vote,
     staged_txn: Option<psbt::Psbt>,
+    staged_raw_txn: Option<bitcoin::Transaction>,
+    /// The last vote which was broadcast, if its fee can be bumped
+    sent_txn: Option<SentVote>,
+    /// If the staged transaction is a fee bump, the txid of the vote which was first sent
+    original_txid: Option<Txid>,
+    /// The transaction whose status is shown on the sending screen
+    tracking: Option<Txid>,
+    /// Funded addresses found from the seed words
+    seed_addrs: Vec<AddrInfo>,
+    batch: Vec<BatchEntry>,
+    /// Counts the batches, so that the tasks of an earlier batch leave the current one alone
+    batch_generation: u64,
+    /// Addresses of each key from the keystore, once it is unlocked
+    saved_keys: Vec<Vec<AddrInfo>>,
 }
 
 struct App {
@@ -208,6 +158,7 @@ struct App {
     ui: Weak<SlintApp>,
     secp: Secp256k1<All>,
     rt: tokio::runtime::Runtime,
+    backend: Arc<dyn ChainBackend>,
 }
 unsafe impl Send for App {}
 unsafe impl Sync for App {}
@@ -216,48 +167,63 @@ unsafe impl Sync for App {}
 
 impl App {
     fn wif_key_to_address(self: &Arc<Self>, wif_key: &str) -> Result<AddrInfo> {
-        // println!("Attempting to decode private key: {wif_key}");
-        let data = bitcoin::base58::decode_check(wif_key)?;
-        let compressed = match data.len() {
-            33 => false,
-            34 => true,
-            _ => {
-                bail!("Invalid key size");
+        wallet::wif_key_to_address(wif_key, &self.secp)
+    }
+    /// Use whichever of these addresses of a key has the most coins to vote
+    fn select_address(self: &Arc<Self>, ui: &SlintApp, addrs: Vec<AddrInfo>) {
+        let Some(ai) = addrs.first() else { return };
+        println!("Got address: {}", ai.addr_str);
+        ui.set_address(address_label(ai).into());
+        ui.set_balance("Balance: Loading...".into());
+        ui.set_message("Got address, loading balance...".into());
+        self.rt.spawn(Arc::clone(self).update_address_info(addrs));
+    }
+    fn show_saved_keys(self: &Arc<Self>, ui: &SlintApp, keys: &[String]) {
+        let mut saved = Vec::new();
+        for wif in keys {
+            match wallet::wif_key_to_addresses(wif, &self.secp) {
+                Ok(addrs) => saved.push(addrs),
+                Err(e) => println!("Warn: Invalid key in keystore: {e}"),
             }
-        };
-        // println!("Made key: {data:?}");
-        let sk = bitcoin::PrivateKey {
-            compressed,
-            network: bitcoin::Network::Bitcoin,
-            inner: secp256k1::SecretKey::from_slice(&data[1..33])?,
-        };
-        let pk = sk.public_key(&self.secp);
-        // println!("Made public key: {pk:?}");
-        if let Ok(payload) = bitcoin::address::Payload::p2wpkh(&pk) {
-            Ok(AddrInfo{
-                private_key: sk,
-                public_key: pk,
-                addr_str: encode_address_payload(&payload),
-                change_script: payload,
-            })
-        } else {
-            bail!("Key is not valid p2wkph");
         }
+        let addrs: Vec<slint::SharedString> = saved.iter().map(|addrs|{
+            addrs.iter().map(|ai|ai.addr_str.as_str()).collect::<Vec<_>>().join(" / ").into()
+        }).collect();
+        ui.set_saved_addrs(addrs[..].into());
+        ui.set_keystore_state(2);
+        ui.set_message("Choose a saved key to vote with".into());
+        self.m.lock().unwrap().saved_keys = saved;
     }
-    async fn update_address_info(self: Arc<Self>, addr: AddrInfo) {
-        let bal = explorer::get_balance(&addr.addr_str).await;
-        let txn = get_usable_utxo(&addr.addr_str).await;
-        self.m.lock().unwrap().addr_info = Some(addr);
+    async fn update_address_info(self: Arc<Self>, addrs: Vec<AddrInfo>) {
+        let Some(first) = addrs.first().cloned() else { return };
+        let addr = match wallet::choose_funded(&*self.backend, addrs).await {
+            Ok(ai) => ai,
+            Err(e) => {
+                println!("Warn: Unable to check the addresses of the key: {e}");
+                first
+            }
+        };
+        let label = address_label(&addr);
+        let bal = self.backend.get_balance(&addr.addr_str).await;
+        let txn = get_address_utxos(&*self.backend, &addr.addr_str).await;
+        let fee_rate = wallet::choose_fee_rate(&*self.backend, None).await;
+        {
+            let mut m = self.m.lock().unwrap();
+            m.addr_info = Some(addr);
+            m.fee_rate_estimate = Some(fee_rate);
+        }
         let ui = self.ui.clone();
         if let Err(e) = ui.upgrade_in_event_loop(move |ui| {
+            ui.set_address(label.into());
+            ui.set_default_fee_rate(format!("{}", wallet::fee_rate_per_vb(fee_rate)).into());
             match bal {
                 Ok(balance) => {
-                    ui.set_balance(format!("Balance: {balance}").into());
-                    if balance > 0.0 {
+                    ui.set_balance(format!("Balance: {}", amount_pkt(&balance)).into());
+                    if balance > Amount::ZERO {
                         match txn {
-                            Ok(spend_txout) => {
+                            Ok(utxos) => {
                                 let mut m = self.m.lock().unwrap();
-                                m.spend_txout = Some(spend_txout);
+                                m.utxos = utxos;
                                 ui.set_vote_ok(true);
                                 ui.set_message("Please specify an address to vote for.".into());
                             }
@@ -277,83 +243,347 @@ impl App {
             println!("Warn: Unable to upgrade ui: {e}");
         }
     }
-    fn make_vote(self: &Arc<Self>) -> Result<psbt::Psbt> {
+    fn make_vote(self: &Arc<Self>, fee_rate: Option<FeeRate>) -> Result<psbt::Psbt> {
         let m = self.m.lock().unwrap();
-        let Some((op, txout)) = &m.spend_txout else {
+        if m.utxos.is_empty() {
             bail!("No outpoint, the app is in a wrong state");
-        };
+        }
         let Some(ai) = &m.addr_info else {
             bail!("No address info, the app is in a wrong state");
         };
-        let vote_for = &m.vote_for;
-        let is_candidate = m.is_candidate;
+        let fee_rate = fee_rate.or(m.fee_rate_estimate).unwrap_or(wallet::DEFAULT_FEE_RATE);
+        wallet::make_vote(ai, &m.utxos, m.consolidate, fee_rate, &m.vote)
+    }
 
-        // Decide fee
-        // We don't have a good way to choose a fee so we're going to pick 500 units
-        // because that's over-paying by about double.
-        let fee = Amount::from_sat(500);
+    /// Look up the previous transactions which the vote needs and show it for confirmation
+    async fn review_vote(self: Arc<Self>, mut psbt: psbt::Psbt) {
+        let res = wallet::add_non_witness_utxos(&*self.backend, &mut psbt).await;
+        let ui = self.ui.clone();
+        let _ = ui.upgrade_in_event_loop(move |ui| {
+            if let Err(e) = res {
+                ui.set_message(format!("Error creating vote: {e}").into());
+                return;
+            }
+            let desc = describe_txn(&psbt);
+            let mut m = self.m.lock().unwrap();
+            m.staged_txn = Some(psbt);
+            m.original_txid = None;
+            let watch_only = m.addr_info.as_ref().map(|ai|ai.is_watch_only()).unwrap_or(false);
+            ui.set_confirm_mode(if watch_only { 1 } else { 0 });
+            ui.set_confirm_txn(desc);
+            ui.set_window(1);
+        });
+    }
 
-        if txout.value < fee {
-            bail!("Unable to make transaction because input is not enough to pay fee");
-        }
+    /// Export a PSBT which still needs the signatures of other cosigners
+    fn export_partial(self: &Arc<Self>, ui: &SlintApp, psbt: &psbt::Psbt, missing: &str) {
+        ui.set_exported(UiExport{
+            title: "Partially signed transaction (PSBT)".into(),
+            data: wallet::encode_psbt(psbt).into(),
+            message: format!("Not complete yet, pass this on to the next cosigner.\n{missing}").into(),
+        });
+        ui.set_export_path("vote.psbt".into());
+        ui.set_window(3);
+    }
+
+    async fn review_raw_txn(self: Arc<Self>, txn: bitcoin::Transaction) {
+        let prevouts = wallet::fetch_prevouts(&*self.backend, &txn).await;
+        let ui = self.ui.clone();
+        let _ = ui.upgrade_in_event_loop(move |ui| {
+            let desc = describe_tx_parts(&prevouts, &txn.output, txn.vsize() as u64);
+            let mut m = self.m.lock().unwrap();
+            m.staged_raw_txn = Some(txn);
+            m.original_txid = None;
+            ui.set_confirm_mode(3);
+            ui.set_confirm_txn(desc);
+            ui.set_window(1);
+        });
+    }
+
+    async fn scan_seed(
+        self: Arc<Self>,
+        account: bitcoin::bip32::Xpriv,
+        origin: bitcoin::bip32::KeySource,
+    ) {
+        let res = seed::discover(&*self.backend, &account, &origin, &self.secp).await;
+        let ui = self.ui.clone();
+        let _ = ui.upgrade_in_event_loop(move |ui| {
+            match res {
+                Ok(found) if found.is_empty() => {
+                    ui.set_message("No address of this seed has any coins".into());
+                }
+                Ok(found) => {
+                    let addrs: Vec<_> = found.iter().map(|sa|UiSeedAddr{
+                        path: sa.path.to_string().into(),
+                        address: sa.ai.addr_str.clone().into(),
+                        balance: amount_pkt(&sa.balance) as f32,
+                    }).collect();
+                    ui.set_seed_addrs(addrs[..].into());
+                    ui.set_message("Choose the address to vote with".into());
+                    self.m.lock().unwrap().seed_addrs = found.into_iter().map(|sa|sa.ai).collect();
+                }
+                Err(e) => {
+                    ui.set_message(format!("Error scanning seed: {e}").into());
+                }
+            }
+        });
+    }
 
-        // make pbst
-        let tx = bitcoin::Transaction {
-            version: bitcoin::transaction::Version::TWO,
-            lock_time: bitcoin::absolute::LockTime::ZERO,
-            input: vec![TxIn{
-                previous_output: op.clone(),
-                ..Default::default()
-            }],
-            output: vec![
-                TxOut{
-                    value: txout.value - fee,
-                    script_pubkey: ai.change_script.script_pubkey(),
+    /// Push the batch status table to the ui, and the stage if it changed
+    fn show_batch(self: &Arc<Self>, stage: Option<i32>) {
+        let rows: Vec<_> = self.m.lock().unwrap().batch.iter().map(|e|e.row.clone()).collect();
+        let _ = self.ui.upgrade_in_event_loop(move |ui| {
+            ui.set_batch_rows(rows[..].into());
+            if let Some(stage) = stage {
+                ui.set_batch_stage(stage);
+            }
+        });
+    }
+
+    async fn prepare_batch(self: Arc<Self>, generation: u64, keys: Vec<Vec<AddrInfo>>, fee_rate: Option<FeeRate>) {
+        let fee_rate = wallet::choose_fee_rate(&*self.backend, fee_rate).await;
+        let keys = batch::funded(&*self.backend, keys).await;
+        let (consolidate, vote) = {
+            let mut m = self.m.lock().unwrap();
+            // Cancelled
+            if m.batch_generation != generation {
+                return;
+            }
+            m.batch = keys.iter().map(|ai|BatchEntry{
+                row: UiBatchRow{
+                    address: ai.addr_str.clone().into(),
+                    amount: 0.0,
+                    fee: 0.0,
+                    status: "Loading...".into(),
+                    error: false,
                 },
-                mk_vote_output(vote_for.clone(), is_candidate),
-            ],
+                ai: ai.clone(),
+                psbt: None,
+            }).collect();
+            (m.consolidate, m.vote.clone())
         };
-        let mut tx = psbt::Psbt::from_unsigned_tx(tx)?;
-
-        // update pbst
-        tx.inputs = vec![Input {
-            witness_utxo: Some(txout.clone()),
-            redeem_script: Some({
-                let Some(wpkh) = ai.public_key.wpubkey_hash() else {
-                    bail!("public_key.wpubkey_hash() did not work correctly");
-                };
-                ScriptBuf::new_p2wpkh(&wpkh)
-            }),
-            sighash_type: Some(PsbtSighashType::from_str("SIGHASH_ALL")?),
-            bip32_derivation: {
-                // We need to create a key derivation path, even though we don't have one.
-                // but it doesn't need to be meaningful because the signer will attempt to
-                // get our privatekey directly from the pubkey if getting it from the path
-                // fails.
-                let mut map = BTreeMap::new();
-                map.insert(ai.public_key.inner, (
-                    Fingerprint::from([1u8, 2, 3, 42]),
-                    DerivationPath::from_str("m/0'")?,
-                ));
-                map
-            },
-            ..Default::default()
-        }];
-
-        Ok(tx)
+        self.show_batch(None);
+        for (i, ai) in keys.iter().enumerate() {
+            let res = batch::prepare(&*self.backend, ai, consolidate, fee_rate, &vote).await;
+            {
+                let mut m = self.m.lock().unwrap();
+                if m.batch_generation != generation {
+                    return;
+                }
+                let Some(entry) = m.batch.get_mut(i) else { return };
+                match res {
+                    Ok(psbt) => {
+                        let spent: Amount = wallet::psbt_prevouts(&psbt).iter()
+                            .filter_map(|(_, prevout)|prevout.as_ref())
+                            .map(|txout|txout.value)
+                            .sum();
+                        entry.row.amount = amount_pkt(&spent) as f32;
+                        entry.row.fee = wallet::psbt_fee(&psbt).map(|fee|amount_pkt(&fee) as f32).unwrap_or(-1.0);
+                        entry.row.status = "Ready".into();
+                        entry.psbt = Some(psbt);
+                    }
+                    Err(e) => {
+                        entry.row.status = format!("Skipped: {e}").into();
+                        entry.row.error = true;
+                    }
+                }
+            }
+            self.show_batch(None);
+        }
+        let any_ready = self.m.lock().unwrap().batch.iter().any(|e|e.psbt.is_some());
+        self.show_batch(Some(if any_ready { 1 } else { 3 }));
+    }
+
+    async fn send_batch(self: Arc<Self>) {
+        let (n, generation) = {
+            let m = self.m.lock().unwrap();
+            (m.batch.len(), m.batch_generation)
+        };
+        for i in 0..n {
+            let (ai, psbt) = {
+                let mut m = self.m.lock().unwrap();
+                if m.batch_generation != generation {
+                    return;
+                }
+                let Some(entry) = m.batch.get_mut(i) else { return };
+                let Some(psbt) = entry.psbt.take() else { continue };
+                entry.row.status = "Sending...".into();
+                (entry.ai.clone(), psbt)
+            };
+            self.show_batch(None);
+            let res = batch::send(&*self.backend, &ai, psbt, &self.secp).await;
+            {
+                let mut m = self.m.lock().unwrap();
+                if m.batch_generation != generation {
+                    return;
+                }
+                let Some(entry) = m.batch.get_mut(i) else { return };
+                match res {
+                    Ok(tx) => entry.row.status = format!("Sent {}", tx.txid()).into(),
+                    Err(e) => {
+                        entry.row.status = format!("Failed: {e}").into();
+                        entry.row.error = true;
+                    }
+                }
+            }
+            self.show_batch(None);
+        }
+        self.show_batch(Some(3));
+    }
+
+    async fn bump_fee(self: Arc<Self>, sent: SentVote, fee_rate: Option<FeeRate>) {
+        let original = &sent.txn;
+        let prevouts = wallet::fetch_prevouts(&*self.backend, original).await;
+        let fee_rate = wallet::choose_fee_rate(&*self.backend, fee_rate).await;
+        let res = async {
+            let mut psbt = wallet::bump_vote(&sent.ai, original, &prevouts, fee_rate)?;
+            wallet::add_non_witness_utxos(&*self.backend, &mut psbt).await?;
+            Ok::<psbt::Psbt, anyhow::Error>(psbt)
+        }.await;
+        let ui = self.ui.clone();
+        let _ = ui.upgrade_in_event_loop(move |ui| {
+            let mut m = self.m.lock().unwrap();
+            match res {
+                Ok(psbt) => {
+                    ui.set_confirm_txn(describe_txn(&psbt));
+                    m.staged_txn = Some(psbt);
+                    // The app may have moved on to another address since the vote was sent
+                    m.addr_info = Some(sent.ai.clone());
+                    m.original_txid = Some(sent.original_txid.unwrap_or(sent.txn.txid()));
+                    ui.set_confirm_mode(0);
+                    ui.set_window(1);
+                }
+                Err(e) => {
+                    ui.set_sending(UiSending{
+                        state: 2,
+                        txid: sent.txn.txid().to_string().into(),
+                        message: "Unable to bump fee".into(),
+                        error: format!("{e}").into(),
+                        replaces: "".into(),
+                        can_bump: false,
+                    });
+                }
+            }
+        });
+    }
+
+    async fn cpfp(self: Arc<Self>, addrs: Vec<AddrInfo>, parent: String, fee_rate: Option<FeeRate>) {
+        let res = async {
+            let parent = wallet::load_txn(&*self.backend, &parent).await?;
+            // Whichever address of the key the parent pays to
+            let Some(ai) = parent.output.iter()
+                .find_map(|txout|addrs.iter().find(|ai|ai.change_script == txout.script_pubkey))
+                .or(addrs.first())
+                .cloned()
+            else {
+                bail!("No address to spend from");
+            };
+            let prevouts = wallet::fetch_prevouts(&*self.backend, &parent).await;
+            let unspent = self.backend.get_utxos(&ai.addr_str, true).await?;
+            let fee_rate = wallet::choose_fee_rate(&*self.backend, fee_rate).await;
+            let mut psbt = wallet::child_pays_for_parent(&ai, &parent, &prevouts, &unspent, fee_rate)?;
+            wallet::add_non_witness_utxos(&*self.backend, &mut psbt).await?;
+            Ok((psbt, ai))
+        }.await;
+        let ui = self.ui.clone();
+        let _ = ui.upgrade_in_event_loop(move |ui| {
+            match res {
+                Ok((psbt, ai)) => {
+                    ui.set_confirm_txn(describe_txn(&psbt));
+                    let mut m = self.m.lock().unwrap();
+                    m.staged_txn = Some(psbt);
+                    m.addr_info = Some(ai);
+                    m.original_txid = None;
+                    ui.set_confirm_mode(0);
+                    ui.set_window(1);
+                }
+                Err(e) => {
+                    ui.set_message(format!("Unable to speed up transaction: {e}").into());
+                }
+            }
+        });
+    }
+
+    /// Keep checking where the transaction is, until it is deep enough in the chain
+    /// or the user moves on.
+    async fn track_txn(self: Arc<Self>, txn: bitcoin::Transaction) {
+        let txid = txn.txid();
+        let mut tracker = wallet::Tracker::new(txn);
+        loop {
+            tokio::time::sleep(TRACK_INTERVAL).await;
+            if self.m.lock().unwrap().tracking != Some(txid) {
+                return;
+            }
+            let (message, alert, done) = match tracker.poll(&*self.backend).await {
+                Ok(Tracked::Waiting) => {
+                    ("Waiting for the transaction to appear...".to_owned(), String::new(), false)
+                }
+                Ok(Tracked::Mempool) => {
+                    ("In the mempool, waiting to be mined".to_owned(), String::new(), false)
+                }
+                Ok(Tracked::Confirmed{ height, confirmations }) => (
+                    format!("Confirmed in block {height}, {confirmations} confirmations"),
+                    String::new(),
+                    confirmations >= TRACK_CONFIRMATIONS,
+                ),
+                Ok(Tracked::Gone(Ok(true))) => (
+                    "Not found".to_owned(),
+                    "The transaction has disappeared from the mempool, bump the fee to send it again".to_owned(),
+                    false,
+                ),
+                Ok(Tracked::Gone(Ok(false))) => (
+                    "Not found".to_owned(),
+                    "The coins of this transaction were spent by a different transaction (double-spend)".to_owned(),
+                    true,
+                ),
+                Ok(Tracked::Gone(Err(e))) => (
+                    "Not found".to_owned(),
+                    format!("The transaction has disappeared from the mempool: {e}"),
+                    false,
+                ),
+                Err(e) => (format!("Unable to check the transaction: {e}"), String::new(), false),
+            };
+            let app = Arc::clone(&self);
+            let _ = self.ui.upgrade_in_event_loop(move |ui| {
+                if app.m.lock().unwrap().tracking == Some(txid) {
+                    ui.set_sending_status(UiTxStatus{ message: message.into(), alert: alert.into() });
+                }
+            });
+            if done {
+                return;
+            }
+        }
     }
 
     async fn bcast_txn(self: Arc<Self>, txn: bitcoin::Transaction) {
-        let res = explorer::bcast_transaction(&txn).await;
-        let _ = self.ui.upgrade_in_event_loop(move |ui| {
+        let res = self.backend.bcast_transaction(&txn).await;
+        let ui = self.ui.clone();
+        let _ = ui.upgrade_in_event_loop(move |ui| {
             match res {
                 Ok(()) => {
+                    let mut m = self.m.lock().unwrap();
+                    let can_bump = txn.is_explicitly_rbf() &&
+                        m.addr_info.as_ref().map(|ai|!ai.is_watch_only()).unwrap_or(false);
                     ui.set_sending(UiSending{
                         state: 1,
                         txid: txn.txid().to_string().into(),
                         message: "Transaction sent successfully".into(),
                         error: "".into(),
+                        replaces: m.original_txid.map(|t|t.to_string()).unwrap_or_default().into(),
+                        can_bump,
+                    });
+                    m.tracking = Some(txn.txid());
+                    m.sent_txn = m.addr_info.clone().filter(|_|can_bump).map(|ai|SentVote{
+                        txn: txn.clone(),
+                        ai,
+                        original_txid: m.original_txid,
                     });
+                    ui.set_bump_fee_rate("".into());
+                    ui.set_sending_status(UiTxStatus{
+                        message: "Waiting for the transaction to appear...".into(),
+                        alert: "".into(),
+                    });
+                    self.rt.spawn(Arc::clone(&self).track_txn(txn));
                 }
                 Err(e) => {
                     ui.set_sending(UiSending{
@@ -361,6 +591,8 @@ impl App {
                         txid: txn.txid().to_string().into(),
                         message: "Failed to send transaction".into(),
                         error: format!("{e}").into(),
+                        replaces: "".into(),
+                        can_bump: false,
                     });
                 }
             }
@@ -368,27 +600,57 @@ impl App {
     }
 }
 
+/// The app is built without a console on Windows, so the command line needs to use the console of
+/// the shell which started it, if there is one.
+#[cfg(windows)]
+fn attach_console() {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
 fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        #[cfg(windows)]
+        attach_console();
+        return cli::main(&args);
+    }
+
+    network::init(None)?;
     let ui = SlintApp::new()?;
     let app = Arc::new(App {
         m: Default::default(),
         ui: ui.as_weak(),
         secp: Secp256k1::new(),
         rt: tokio::runtime::Runtime::new()?,
+        backend: backend::from_env()?,
     });
 
+    if keystore::default_path().exists() {
+        ui.set_keystore_state(1);
+        ui.set_message("Enter your password to unlock your saved keys.".into());
+    }
+
     ui.on_compute_address({
         let app = Arc::clone(&app);
         move || {
             let ui = app.ui.upgrade().unwrap();
             let sk = ui.get_private_key();
-            match app.wif_key_to_address(&sk) {
+            let res = match wallet::wif_key_to_addresses(&sk, &app.secp) {
+                _ if multisig::is_descriptor(&sk) => multisig::descriptor_address(&sk, 0).map(|ai|vec![ai]),
+                Err(e) if script_from_address(sk.trim()).is_err() => Err(e),
+                Ok(s) => Ok(s),
+                Err(_) => wallet::watch_only_address(&sk).map(|ai|vec![ai]),
+            };
+            match res {
                 Ok(s) => {
-                    println!("Got address: {}", s.addr_str);
-                    ui.set_address(format!("Address: {}", s.addr_str).into());
-                    ui.set_balance("Balance: Loading...".into());
-                    ui.set_message("Got address, loading balance...".into());
-                    app.rt.spawn(Arc::clone(&app).update_address_info(s));
+                    app.select_address(&ui, s);
                 }
                 Err(e) => {
                     ui.set_message(format!("Private key error: {e}").into());
@@ -397,16 +659,87 @@ fn main() -> Result<()> {
         }
     });
 
+    ui.on_scan_seed({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let path = ui.get_seed_path();
+            let path = Some(path.as_str()).filter(|p|!p.trim().is_empty());
+            match seed::account_key(&ui.get_seed_words(), &ui.get_seed_passphrase(), path, &app.secp) {
+                Ok((account, origin)) => {
+                    ui.set_message("Looking for addresses with coins...".into());
+                    app.rt.spawn(Arc::clone(&app).scan_seed(account, origin));
+                }
+                Err(e) => {
+                    ui.set_message(format!("{e}").into());
+                }
+            }
+        }
+    });
+
+    ui.on_use_seed_addr({
+        let app = Arc::clone(&app);
+        move |i| {
+            let ui = app.ui.upgrade().unwrap();
+            let Some(ai) = app.m.lock().unwrap().seed_addrs.get(i as usize).cloned() else {
+                return;
+            };
+            app.select_address(&ui, vec![ai]);
+        }
+    });
+
+    ui.on_unlock_keystore({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            match keystore::load(&keystore::default_path(), &ui.get_keystore_password()) {
+                Ok(keys) => app.show_saved_keys(&ui, &keys),
+                Err(e) => ui.set_message(format!("{e}").into()),
+            }
+        }
+    });
+
+    ui.on_save_key({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let wif = ui.get_private_key();
+            let wif = wif.trim();
+            if let Err(e) = app.wif_key_to_address(wif) {
+                ui.set_message(format!("Private key error: {e}").into());
+                return;
+            }
+            match keystore::add(&keystore::default_path(), &ui.get_keystore_password(), wif) {
+                Ok(keys) => {
+                    app.show_saved_keys(&ui, &keys);
+                    ui.set_message("Private key saved".into());
+                }
+                Err(e) => ui.set_message(format!("Unable to save private key: {e}").into()),
+            }
+        }
+    });
+
+    ui.on_use_saved_key({
+        let app = Arc::clone(&app);
+        move |i| {
+            let ui = app.ui.upgrade().unwrap();
+            let Some(addrs) = app.m.lock().unwrap().saved_keys.get(i as usize).cloned() else {
+                return;
+            };
+            app.select_address(&ui, addrs);
+        }
+    });
+
     ui.on_check_vote_for_address({
         let app = Arc::clone(&app);
         move || {
             let ui = app.ui.upgrade().unwrap();
+            ui.set_vote_for_chars(Default::default());
             let vfn = ui.get_vote_for_nobody();
             let is_candidate = ui.get_is_candidate();
+            app.m.lock().unwrap().consolidate = ui.get_consolidate();
             if vfn {
-                let mut m = app.m.lock().unwrap();
-                m.is_candidate = is_candidate;
-                m.vote_for = None;
+                app.m.lock().unwrap().vote = Vote{ vote_for: None, is_candidate };
                 ui.set_vote_for_ok(true);
                 ui.set_message("Ready to vote".into());
                 return;
@@ -416,16 +749,21 @@ fn main() -> Result<()> {
                 ui.set_message("Please specify an address to vote for.".into());
                 return;
             }
-            match script_from_address(&addr) {
-                Ok(scr) => {
-                    let mut m = app.m.lock().unwrap();
-                    m.is_candidate = is_candidate;
-                    m.vote_for = Some(scr);
+            match PktAddress::parse(&addr) {
+                Ok(addr) => {
+                    app.m.lock().unwrap().vote = Vote{ vote_for: Some(addr.script_pubkey()), is_candidate };
                     ui.set_vote_for_ok(true);
                     ui.set_message("Ready to vote".into());
                 }
                 Err(e) => {
+                    let chars: Vec<_> = addr.trim().chars().enumerate().map(|(i, c)|UiAddrChar{
+                        text: c.to_string().into(),
+                        bad: e.positions.contains(&i),
+                    }).collect();
                     ui.set_vote_for_ok(false);
+                    if !e.positions.is_empty() {
+                        ui.set_vote_for_chars(chars[..].into());
+                    }
                     ui.set_message(format!("Unable to handle vote for address: {e}").into());
                 }
             }
@@ -436,17 +774,171 @@ fn main() -> Result<()> {
         let app = Arc::clone(&app);
         move || {
             let ui = app.ui.upgrade().unwrap();
-            match app.make_vote() {
+            let fee_rate = match ui_fee_rate(&ui) {
+                Ok(fee_rate) => fee_rate,
+                Err(e) => {
+                    ui.set_message(format!("{e}").into());
+                    return;
+                }
+            };
+            match app.make_vote(fee_rate) {
+                Ok(psbt) => {
+                    app.rt.spawn(Arc::clone(&app).review_vote(psbt));
+                }
+                Err(e) => {
+                    ui.set_message(format!("Error creating vote: {e}").into());
+                }
+            }
+        }
+    });
+
+    ui.on_load_psbt({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            // No network access here, this is used for signing on an offline computer.
+            let ai = match app.wif_key_to_address(&ui.get_private_key()) {
+                Ok(ai) => ai,
+                Err(e) => {
+                    ui.set_message(format!("Private key error: {e}").into());
+                    return;
+                }
+            };
+            match wallet::load_psbt(&ui.get_txn_in()) {
                 Ok(psbt) => {
                     let desc = describe_txn(&psbt);
-                    app.m.lock().unwrap().staged_txn = Some(psbt);
+                    let mut m = app.m.lock().unwrap();
+                    m.staged_txn = Some(psbt);
+                    m.addr_info = Some(ai);
+                    ui.set_confirm_mode(2);
                     ui.set_confirm_txn(desc);
                     ui.set_window(1);
                 }
                 Err(e) => {
-                    ui.set_message(format!("Error creating vote: {e}").into());
+                    ui.set_message(format!("Unable to load PSBT: {e}").into());
+                }
+            }
+        }
+    });
+
+    ui.on_load_raw_txn({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            match wallet::load_raw_txn(&ui.get_txn_in()) {
+                Ok(txn) => {
+                    ui.set_message("Loading transaction inputs...".into());
+                    app.rt.spawn(Arc::clone(&app).review_raw_txn(txn));
+                }
+                Err(e) => {
+                    ui.set_message(format!("Unable to load transaction: {e}").into());
+                }
+            }
+        }
+    });
+
+    ui.on_combine_psbts({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let res = ui.get_txn_in().split_whitespace()
+                .map(wallet::load_psbt)
+                .collect::<Result<Vec<_>>>()
+                .and_then(multisig::combine);
+            let psbt = match res {
+                Ok(psbt) => psbt,
+                Err(e) => {
+                    ui.set_message(format!("Unable to combine PSBTs: {e}").into());
+                    return;
                 }
+            };
+            if let Some(missing) = multisig::describe_missing(&multisig::signature_status(&psbt)) {
+                app.export_partial(&ui, &psbt, &missing);
+                return;
             }
+            match wallet::finalize_psbt(psbt, &app.secp) {
+                Ok(txn) => {
+                    ui.set_message("Loading transaction inputs...".into());
+                    app.rt.spawn(Arc::clone(&app).review_raw_txn(txn));
+                }
+                Err(e) => {
+                    ui.set_message(format!("Unable to finalize transaction: {e}").into());
+                }
+            }
+        }
+    });
+
+    ui.on_cpfp({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let addrs = match wallet::wif_key_to_addresses(&ui.get_private_key(), &app.secp) {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    ui.set_message(format!("Private key error: {e}").into());
+                    return;
+                }
+            };
+            let fee_rate = match ui_fee_rate(&ui) {
+                Ok(fee_rate) => fee_rate,
+                Err(e) => {
+                    ui.set_message(format!("{e}").into());
+                    return;
+                }
+            };
+            ui.set_message("Loading transaction...".into());
+            app.rt.spawn(Arc::clone(&app).cpfp(addrs, ui.get_txn_in().into(), fee_rate));
+        }
+    });
+
+    ui.on_review_batch({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let keys = match batch::parse_keys(&ui.get_batch_keys(), &app.secp) {
+                Ok(keys) => keys,
+                Err(e) => {
+                    ui.set_message(format!("{e}").into());
+                    return;
+                }
+            };
+            let fee_rate = match ui_fee_rate(&ui) {
+                Ok(fee_rate) => fee_rate,
+                Err(e) => {
+                    ui.set_message(format!("{e}").into());
+                    return;
+                }
+            };
+            let generation = {
+                let mut m = app.m.lock().unwrap();
+                m.batch.clear();
+                m.batch_generation += 1;
+                m.batch_generation
+            };
+            app.show_batch(Some(0));
+            ui.set_window(4);
+            app.rt.spawn(Arc::clone(&app).prepare_batch(generation, keys, fee_rate));
+        }
+    });
+
+    ui.on_batch_send({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            ui.set_batch_stage(2);
+            app.rt.spawn(Arc::clone(&app).send_batch());
+        }
+    });
+
+    ui.on_batch_done({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let mut m = app.m.lock().unwrap();
+            m.batch.clear();
+            m.batch_generation += 1;
+            drop(m);
+            ui.set_window(0);
         }
     });
 
@@ -455,7 +947,9 @@ fn main() -> Result<()> {
         move || {
             println!("on_confirm_cancel");
             let ui = app.ui.upgrade().unwrap();
-            app.m.lock().unwrap().staged_txn = None;
+            let mut m = app.m.lock().unwrap();
+            m.staged_txn = None;
+            m.staged_raw_txn = None;
             ui.set_window(0);
         }
     });
@@ -464,11 +958,84 @@ fn main() -> Result<()> {
         let app = Arc::clone(&app);
         move || {
             let ui = app.ui.upgrade().unwrap();
+            match ui.get_confirm_mode() {
+                // Watch-only, export the unsigned PSBT
+                1 => {
+                    let m = app.m.lock().unwrap();
+                    let Some(psbt) = &m.staged_txn else {
+                        ui.set_message("No staged transaction, app in wrong state".into());
+                        ui.set_window(0);
+                        return;
+                    };
+                    ui.set_exported(UiExport{
+                        title: "Unsigned vote transaction (PSBT)".into(),
+                        data: wallet::encode_psbt(psbt).into(),
+                        message: "Copy this, or save it to a file, and sign it on the computer which holds the private key.".into(),
+                    });
+                    ui.set_export_path("vote.psbt".into());
+                    ui.set_window(3);
+                    return;
+                }
+                // Offline signing, export the signed transaction
+                2 => {
+                    let mut m = app.m.lock().unwrap();
+                    let res = match (m.staged_txn.take(), &m.addr_info) {
+                        (Some(psbt), Some(ai)) => wallet::sign_psbt(ai, psbt, &app.secp),
+                        _ => Err(anyhow::anyhow!("No staged transaction, app in wrong state")),
+                    };
+                    let res = res.and_then(|psbt|{
+                        match multisig::describe_missing(&multisig::signature_status(&psbt)) {
+                            Some(missing) => Ok(Err((psbt, missing))),
+                            None => wallet::finalize_psbt(psbt, &app.secp).map(Ok),
+                        }
+                    });
+                    match res {
+                        Ok(Ok(tx)) => {
+                            ui.set_exported(UiExport{
+                                title: "Signed transaction".into(),
+                                data: encode::serialize_hex(&tx).into(),
+                                message: "Copy this, or save it to a file, and broadcast it from an online computer.".into(),
+                            });
+                            ui.set_export_path("vote.hex".into());
+                            ui.set_window(3);
+                        }
+                        // Multisig which still needs other cosigners
+                        Ok(Err((psbt, missing))) => app.export_partial(&ui, &psbt, &missing),
+                        Err(e) => {
+                            ui.set_message(format!("Failed to sign transaction: {e}").into());
+                            ui.set_window(0);
+                        }
+                    }
+                    return;
+                }
+                // Already signed, just broadcast it
+                3 => {
+                    let Some(tx) = app.m.lock().unwrap().staged_raw_txn.take() else {
+                        ui.set_message("No staged transaction, app in wrong state".into());
+                        ui.set_window(0);
+                        return;
+                    };
+                    ui.set_sending(UiSending{
+                        state: 0,
+                        txid: tx.txid().to_string().into(),
+                        message: "Uploading transaction...".into(),
+                        error: "".into(),
+                        replaces: "".into(),
+                        can_bump: false,
+                    });
+                    ui.set_window(2);
+                    app.rt.spawn(Arc::clone(&app).bcast_txn(tx));
+                    return;
+                }
+                _ => {}
+            }
             ui.set_sending(UiSending{
                 state: 0,
                 txid: "".into(),
                 message: "Signing transaction".into(),
                 error: "".into(),
+                replaces: "".into(),
+                can_bump: false,
             });
             ui.set_window(2);
 
@@ -479,6 +1046,8 @@ fn main() -> Result<()> {
                     txid: "".into(),
                     message: "".into(),
                     error: "No staged transaction, app in wrong state".into(),
+                    replaces: "".into(),
+                    can_bump: false,
                 });
                 return;
             };
@@ -488,6 +1057,8 @@ fn main() -> Result<()> {
                     txid: "".into(),
                     message: "".into(),
                     error: "No address info, app in wrong state".into(),
+                    replaces: "".into(),
+                    can_bump: false,
                 });
                 return;
             };
@@ -499,6 +1070,8 @@ fn main() -> Result<()> {
                         txid: "".into(),
                         message: "".into(),
                         error: format!("Failed to sign transaction: {e}").into(),
+                        replaces: "".into(),
+                        can_bump: false,
                     });
                     return;
                 }
@@ -508,6 +1081,8 @@ fn main() -> Result<()> {
                 txid: tx.txid().to_string().into(),
                 message: "Transaction signed, uploading...".into(),
                 error: "".into(),
+                replaces: "".into(),
+                can_bump: false,
             });
             println!("Tx content: {}", encode::serialize_hex(&tx));
             app.rt.spawn(Arc::clone(&app).bcast_txn(tx));
@@ -515,6 +1090,85 @@ fn main() -> Result<()> {
     });
 
     ui.on_sending_done({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let mut m = app.m.lock().unwrap();
+            m.staged_txn = None;
+            m.tracking = None;
+            ui.set_can_bump_sent(m.sent_txn.is_some());
+            ui.set_window(0);
+        }
+    });
+
+    ui.on_bump_fee({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let Some(sent) = app.m.lock().unwrap().sent_txn.clone() else {
+                return;
+            };
+            let fee_rate = ui.get_bump_fee_rate();
+            let fee_rate = match Some(fee_rate.trim()).filter(|f|!f.is_empty()).map(wallet::parse_fee_rate).transpose() {
+                Ok(fee_rate) => fee_rate,
+                Err(e) => {
+                    let mut status = ui.get_sending_status();
+                    status.alert = format!("{e}").into();
+                    ui.set_sending_status(status);
+                    return;
+                }
+            };
+            app.m.lock().unwrap().tracking = None;
+            ui.set_sending(UiSending{
+                state: 0,
+                txid: sent.txn.txid().to_string().into(),
+                message: "Building replacement transaction...".into(),
+                error: "".into(),
+                replaces: "".into(),
+                can_bump: false,
+            });
+            app.rt.spawn(Arc::clone(&app).bump_fee(sent, fee_rate));
+        }
+    });
+
+    ui.on_show_sent({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let mut m = app.m.lock().unwrap();
+            let Some(sent) = m.sent_txn.clone() else {
+                return;
+            };
+            m.tracking = Some(sent.txn.txid());
+            ui.set_sending(UiSending{
+                state: 1,
+                txid: sent.txn.txid().to_string().into(),
+                message: "Vote sent earlier".into(),
+                error: "".into(),
+                replaces: sent.original_txid.map(|t|t.to_string()).unwrap_or_default().into(),
+                can_bump: true,
+            });
+            ui.set_sending_status(UiTxStatus{ message: "Checking the transaction...".into(), alert: "".into() });
+            ui.set_window(2);
+            app.rt.spawn(Arc::clone(&app).track_txn(sent.txn));
+        }
+    });
+
+    ui.on_export_save({
+        let app = Arc::clone(&app);
+        move || {
+            let ui = app.ui.upgrade().unwrap();
+            let mut exported = ui.get_exported();
+            let path = ui.get_export_path();
+            exported.message = match std::fs::write(path.as_str(), format!("{}\n", exported.data)) {
+                Ok(()) => format!("Saved to {path}").into(),
+                Err(e) => format!("Failed to save {path}: {e}").into(),
+            };
+            ui.set_exported(exported);
+        }
+    });
+
+    ui.on_export_done({
         let app = Arc::clone(&app);
         move || {
             let ui = app.ui.upgrade().unwrap();
@@ -0,0 +1,245 @@
+use std::str::FromStr;
+
+use bitcoin::psbt;
+use bitcoin::script::Instruction;
+use bitcoin::PublicKey;
+use bitcoin::Script;
+use miniscript::{Descriptor,DescriptorPublicKey};
+use anyhow::{bail,Result};
+
+use crate::address::PktAddress;
+use crate::wallet::{self,AddrInfo};
+
+/// Whether the argument looks like an output descriptor rather than a key or an address
+pub fn is_descriptor(s: &str) -> bool {
+    s.contains('(')
+}
+
+/// The address of an output descriptor such as wsh(multi(2,KEY1,KEY2,KEY3)) or sh(wpkh(KEY)),
+/// if the descriptor has a wildcard then index chooses which address. The address is watch-only,
+/// the vote is signed by each cosigner in turn by passing the PSBT along.
+pub fn descriptor_address(desc: &str, index: u32) -> Result<AddrInfo> {
+    let desc = match Descriptor::<DescriptorPublicKey>::from_str(desc.trim()) {
+        Ok(desc) => desc,
+        Err(e) => bail!("Invalid descriptor: {e}"),
+    };
+    if let Descriptor::Tr(_) = desc {
+        bail!("Taproot descriptors are not supported on PKT");
+    }
+    if desc.desc_type().segwit_version().is_none() {
+        bail!("Only segwit descriptors are supported (e.g. wsh(multi(...)) or sh(wsh(multi(...)))), not {desc}");
+    }
+    if !desc.has_wildcard() && index != 0 {
+        bail!("Descriptor has no wildcard, so it has only one address");
+    }
+    let desc = match desc.at_derivation_index(index) {
+        Ok(desc) => desc,
+        Err(e) => bail!("Unable to derive address {index} of descriptor: {e}"),
+    };
+    desc.sanity_check()?;
+    let change_script = desc.script_pubkey();
+    let addr = PktAddress::from_script(&change_script)?;
+    Ok(AddrInfo{
+        private_key: None,
+        public_key: None,
+        addr_str: addr.to_string(),
+        change_script,
+        descriptor: Some(desc),
+        key_origin: None,
+    })
+}
+
+/// Merge the signatures and everything else known about the inputs from PSBTs of the same
+/// transaction, e.g. the ones signed by each cosigner.
+pub fn combine(psbts: Vec<psbt::Psbt>) -> Result<psbt::Psbt> {
+    let mut psbts = psbts.into_iter();
+    let Some(mut out) = psbts.next() else {
+        bail!("No PSBT to combine");
+    };
+    for (n, v) in psbts.enumerate() {
+        if v.unsigned_tx.txid() != out.unsigned_tx.txid() {
+            bail!("PSBT number {} is for a different transaction ({}, not {})",
+                n + 2, v.unsigned_tx.txid(), out.unsigned_tx.txid());
+        }
+        out.combine(v)?;
+    }
+    Ok(out)
+}
+
+/// How far one input of a PSBT is from being fully signed
+pub struct InputStatus {
+    pub signatures: usize,
+    pub needed: usize,
+    /// Keys which could still sign, if they are known
+    pub missing: Vec<PublicKey>,
+}
+
+impl InputStatus {
+    pub fn is_complete(&self) -> bool {
+        self.signatures >= self.needed
+    }
+}
+
+/// The signatures which each input of the PSBT has and still needs
+pub fn signature_status(v: &psbt::Psbt) -> Vec<InputStatus> {
+    v.inputs.iter().map(|input| {
+        if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+            return InputStatus{ signatures: 1, needed: 1, missing: Vec::new() };
+        }
+        let script = input.witness_script.as_ref().or(
+            input.redeem_script.as_ref().filter(|s|!s.is_p2wpkh() && !s.is_p2wsh())
+        );
+        let (keys, needed) = match script {
+            Some(script) => (script_keys(script), wallet::script_sigs_needed(script)),
+            None => (input.bip32_derivation.keys().map(|pk|PublicKey::new(*pk)).collect(), 1),
+        };
+        // Signatures by keys which are not in the script do not count towards the threshold
+        let signed = |pk: &PublicKey| input.partial_sigs.keys().any(|s|s.inner == pk.inner);
+        let (signed, missing): (Vec<_>, Vec<_>) = keys.into_iter().partition(signed);
+        InputStatus{ signatures: signed.len(), needed, missing }
+    }).collect()
+}
+
+/// Public keys which appear in a script
+fn script_keys(script: &Script) -> Vec<PublicKey> {
+    script.instructions().filter_map(|ins| match ins {
+        Ok(Instruction::PushBytes(pb)) => PublicKey::from_slice(pb.as_bytes()).ok(),
+        _ => None,
+    }).collect()
+}
+
+/// Human readable summary of the signatures which are still missing, None if there are none
+pub fn describe_missing(status: &[InputStatus]) -> Option<String> {
+    let mut out = Vec::new();
+    for (i, st) in status.iter().enumerate() {
+        if st.is_complete() {
+            continue;
+        }
+        let keys: Vec<_> = st.missing.iter().map(|pk|pk.to_string()).collect();
+        out.push(format!("input {i} has {} of {} signatures, not yet signed by: {}",
+            st.signatures, st.needed, if keys.is_empty() { "unknown".to_owned() } else { keys.join(", ") }));
+    }
+    if out.is_empty() { None } else { Some(out.join("\n")) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::{self,Secp256k1};
+    use bitcoin::{Amount,OutPoint,TxOut};
+
+    use crate::vote::Vote;
+
+    const KEY1: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const KEY2: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+
+    #[test]
+    fn segwit_descriptors_are_accepted() {
+        for desc in [
+            format!("wsh(multi(1,{KEY1},{KEY2}))"),
+            format!("sh(wsh(sortedmulti(1,{KEY1},{KEY2})))"),
+            format!("wpkh({KEY1})"),
+        ] {
+            let ai = descriptor_address(&desc, 0).unwrap();
+            assert_eq!(PktAddress::from_str(&ai.addr_str).unwrap().script_pubkey(), ai.change_script, "{desc}");
+        }
+    }
+
+    #[test]
+    fn non_segwit_descriptors_are_rejected() {
+        for desc in [
+            format!("sh(multi(1,{KEY1},{KEY2}))"),
+            format!("sh(sortedmulti(1,{KEY1},{KEY2}))"),
+            format!("pkh({KEY1})"),
+        ] {
+            let err = descriptor_address(&desc, 0).err().unwrap().to_string();
+            assert!(err.contains("Only segwit descriptors"), "{desc}: {err}");
+        }
+    }
+
+    fn cosigner(n: u8) -> AddrInfo {
+        let sk = secp256k1::SecretKey::from_slice(&[n; 32]).unwrap();
+        wallet::key_to_address(bitcoin::PrivateKey::new(sk, bitcoin::Network::Bitcoin), &Secp256k1::new()).unwrap()
+    }
+
+    /// A 2-of-3 of cosigners 1, 2 and 3
+    fn two_of_three() -> AddrInfo {
+        let keys: Vec<_> = (1..=3).map(|n|cosigner(n).public_key.unwrap().to_string()).collect();
+        descriptor_address(&format!("wsh(multi(2,{}))", keys.join(",")), 0).unwrap()
+    }
+
+    /// An unsigned vote spending a made up coin n of the address
+    fn vote_from(ai: &AddrInfo, n: u8) -> psbt::Psbt {
+        let op = OutPoint::new(bitcoin::Txid::from_byte_array([n; 32]), 0);
+        let txout = TxOut{ value: Amount::from_sat(1_000_000), script_pubkey: ai.change_script.clone() };
+        wallet::make_vote(ai, &[(op, txout)], false, wallet::DEFAULT_FEE_RATE, &Vote::default()).unwrap()
+    }
+
+    #[test]
+    fn two_of_three_is_signed_by_two_cosigners() {
+        let secp = Secp256k1::new();
+        let ms = two_of_three();
+        let psbt = vote_from(&ms, 1);
+        assert!(psbt.inputs[0].witness_script.is_some());
+
+        let psbt = wallet::sign_psbt(&cosigner(1), psbt, &secp).unwrap();
+        let status = signature_status(&psbt);
+        assert_eq!((status[0].signatures, status[0].needed), (1, 2));
+        assert!(!status[0].is_complete());
+        assert!(wallet::finalize_psbt(psbt.clone(), &secp).is_err());
+
+        let psbt = wallet::sign_psbt(&cosigner(3), psbt, &secp).unwrap();
+        assert!(signature_status(&psbt)[0].is_complete());
+        let tx = wallet::finalize_psbt(psbt, &secp).unwrap();
+        // The empty push for CHECKMULTISIG, two signatures and the witness script
+        assert_eq!(tx.input[0].witness.len(), 4);
+        assert!(tx.input[0].script_sig.is_empty());
+    }
+
+    #[test]
+    fn two_of_three_is_not_signed_by_others() {
+        let err = wallet::sign_psbt(&cosigner(4), vote_from(&two_of_three(), 1), &Secp256k1::new())
+            .unwrap_err().to_string();
+        assert!(err.contains("None of the inputs"), "{err}");
+    }
+
+    #[test]
+    fn separately_signed_copies_are_combined() {
+        let secp = Secp256k1::new();
+        let psbt = vote_from(&two_of_three(), 1);
+        let one = wallet::sign_psbt(&cosigner(1), psbt.clone(), &secp).unwrap();
+        let two = wallet::sign_psbt(&cosigner(2), psbt.clone(), &secp).unwrap();
+        assert!(describe_missing(&signature_status(&one)).is_some());
+
+        let both = combine(vec![one, two]).unwrap();
+        assert_eq!(both.inputs[0].partial_sigs.len(), 2);
+        assert_eq!(describe_missing(&signature_status(&both)), None);
+        wallet::finalize_psbt(both, &secp).unwrap();
+    }
+
+    #[test]
+    fn different_transactions_are_not_combined() {
+        let ms = two_of_three();
+        let err = combine(vec![vote_from(&ms, 1), vote_from(&ms, 2)]).unwrap_err().to_string();
+        assert!(err.contains("PSBT number 2 is for a different transaction"), "{err}");
+        assert!(combine(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn missing_signatures_are_reported() {
+        let secp = Secp256k1::new();
+        let mut psbt = wallet::sign_psbt(&cosigner(2), vote_from(&two_of_three(), 1), &secp).unwrap();
+        // A signature by someone else does not count
+        let stranger = cosigner(4).public_key.unwrap();
+        let sig = *psbt.inputs[0].partial_sigs.values().next().unwrap();
+        psbt.inputs[0].partial_sigs.insert(stranger, sig);
+
+        let status = signature_status(&psbt);
+        assert_eq!(status[0].signatures, 1);
+        let missing = [cosigner(1).public_key.unwrap(), cosigner(3).public_key.unwrap()];
+        assert_eq!(status[0].missing, missing);
+        assert_eq!(describe_missing(&status).unwrap(),
+            format!("input 0 has 1 of 2 signatures, not yet signed by: {}, {}", missing[0], missing[1]));
+    }
+}
@@ -0,0 +1,181 @@
+use bitcoin::bech32::Hrp;
+use bitcoin::p2p::Magic;
+use anyhow::{bail,Result};
+
+/// Environment variable which selects the network if none is specified on the command line.
+pub const NETWORK_ENV: &str = "PKT_VOTER_NETWORK";
+
+/// Everything which differs between the PKT chains
+pub struct NetworkParams {
+    pub name: &'static str,
+    /// Version byte of base58 p2pkh addresses
+    pub p2pkh_prefix: u8,
+    /// Version byte of base58 p2sh addresses
+    pub p2sh_prefix: u8,
+    /// Human readable part of bech32 (segwit) addresses
+    pub hrp: &'static str,
+    /// Version byte of WIF private keys
+    pub wif_prefix: u8,
+    /// P2P network magic
+    pub magic: [u8; 4],
+    /// Default P2P port of pktd
+    pub p2p_port: u16,
+    /// Number of units in one coin
+    pub units_per_coin: u64,
+    /// Backend used when none is specified, see backend::from_spec
+    pub default_backend: &'static str,
+    /// Address and transaction APIs of the public block explorers, if the chain has them
+    pub explorer: Option<(&'static str, &'static str)>,
+}
+
+pub const MAINNET: NetworkParams = NetworkParams{
+    name: "mainnet",
+    p2pkh_prefix: 0x75,
+    p2sh_prefix: 0x38,
+    hrp: "pkt",
+    // The same as Bitcoin testnet's, and the same on every PKT network
+    wif_prefix: 0xef,
+    magic: [0x90, 0xf0, 0x2f, 0x08],
+    p2p_port: 64764,
+    units_per_coin: 1 << 30,
+    default_backend: "explorer",
+    explorer: Some(("https://explorer.pkt.cash/api/v1/PKT/pkt", "https://explorer.cjdns.fr/api/v2")),
+};
+
+pub const TESTNET: NetworkParams = NetworkParams{
+    name: "testnet",
+    p2pkh_prefix: 0x6f,
+    p2sh_prefix: 0xc4,
+    hrp: "tpk",
+    wif_prefix: 0xef,
+    magic: [0x0b, 0x11, 0x09, 0x07],
+    p2p_port: 64512,
+    units_per_coin: 1 << 30,
+    default_backend: "pktd:http://localhost:64513",
+    explorer: None,
+};
+
+/// A private chain for rehearsing, e.g. pktd --regtest
+pub const REGTEST: NetworkParams = NetworkParams{
+    name: "regtest",
+    p2pkh_prefix: 0x6f,
+    p2sh_prefix: 0xc4,
+    hrp: "bcrt",
+    wif_prefix: 0xef,
+    magic: [0xfa, 0xbf, 0xb5, 0xda],
+    p2p_port: 18444,
+    units_per_coin: 1 << 30,
+    default_backend: "pktd:http://localhost:18334",
+    explorer: None,
+};
+
+const ALL: &[&NetworkParams] = &[&MAINNET, &TESTNET, &REGTEST];
+
+#[cfg(not(test))]
+static CURRENT: std::sync::OnceLock<&'static NetworkParams> = std::sync::OnceLock::new();
+
+// Tests run on threads of one process, so each of them chooses its own network
+#[cfg(test)]
+thread_local! {
+    static CURRENT: std::cell::OnceCell<&'static NetworkParams> = const { std::cell::OnceCell::new() };
+}
+
+/// The network which is in use, choosing params if none is yet
+fn current_or(params: &'static NetworkParams) -> &'static NetworkParams {
+    #[cfg(not(test))]
+    return CURRENT.get_or_init(||params);
+    #[cfg(test)]
+    return CURRENT.with(|current|*current.get_or_init(||params));
+}
+
+impl NetworkParams {
+    pub fn from_name(name: &str) -> Result<&'static NetworkParams> {
+        match ALL.iter().find(|p|p.name == name) {
+            Some(p) => Ok(p),
+            None => bail!("Unknown network {name}, expected one of: {}",
+                ALL.iter().map(|p|p.name).collect::<Vec<_>>().join(", ")),
+        }
+    }
+
+    pub fn hrp(&self) -> Hrp {
+        Hrp::parse(self.hrp).unwrap()
+    }
+
+    pub fn magic(&self) -> Magic {
+        Magic::from_bytes(self.magic)
+    }
+}
+
+/// Choose the network, by name if one is given, otherwise from the environment, mainnet by
+/// default. This must happen before anything uses current().
+pub fn init(name: Option<&str>) -> Result<()> {
+    let env = std::env::var(NETWORK_ENV).unwrap_or_default();
+    let params = match name.or(Some(env.as_str()).filter(|e|!e.is_empty())) {
+        Some(name) => NetworkParams::from_name(name)?,
+        None => &MAINNET,
+    };
+    let current = current_or(params);
+    if current.name != params.name {
+        bail!("Network is already {}", current.name);
+    }
+    Ok(())
+}
+
+/// The network which is in use
+pub fn current() -> &'static NetworkParams {
+    current_or(&MAINNET)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::address::{AddressEncoding,Payload};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{PubkeyHash,ScriptBuf,WPubkeyHash};
+
+    use super::*;
+    use crate::address::PktAddress;
+    use crate::wallet;
+
+    /// The address of a script with the prefixes of the network
+    fn address_on(params: &NetworkParams, script: &ScriptBuf) -> String {
+        AddressEncoding{
+            payload: &Payload::from_script(script).unwrap(),
+            p2pkh_prefix: params.p2pkh_prefix,
+            p2sh_prefix: params.p2sh_prefix,
+            hrp: params.hrp(),
+        }.to_string()
+    }
+
+    #[test]
+    fn mainnet_is_the_default() {
+        assert_eq!(current().name, "mainnet");
+        assert!(init(Some("testnet")).is_err());
+    }
+
+    #[test]
+    fn init_chooses_the_network() {
+        assert!(init(Some("nonet")).is_err());
+        init(Some("regtest")).unwrap();
+        assert_eq!(current().name, "regtest");
+        init(Some("regtest")).unwrap();
+        let err = init(Some("mainnet")).unwrap_err().to_string();
+        assert!(err.contains("already regtest"), "{err}");
+
+        let p2wpkh = ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20]));
+        for script in [ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([1; 20])), p2wpkh.clone()] {
+            let regtest = address_on(&REGTEST, &script);
+            let addr = PktAddress::parse(&regtest).unwrap();
+            assert_eq!(addr.script_pubkey(), script);
+            assert_eq!(addr.to_string(), regtest);
+            assert!(PktAddress::parse(&address_on(&MAINNET, &script)).is_err());
+        }
+        assert!(address_on(&REGTEST, &p2wpkh).starts_with("bcrt1q"));
+        assert!(PktAddress::parse(&address_on(&TESTNET, &p2wpkh)).is_err());
+
+        let sk = bitcoin::PrivateKey::new(bitcoin::secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
+            bitcoin::Network::Bitcoin);
+        let wif = wallet::encode_wif(&sk);
+        assert_eq!(bitcoin::base58::decode_check(&wif).unwrap()[0], REGTEST.wif_prefix);
+        assert_eq!(wallet::decode_wif(&wif).unwrap(), sk);
+    }
+}
@@ -0,0 +1,326 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+use async_trait::async_trait;
+use bitcoin::consensus;
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::{Magic,ServiceFlags};
+use bitcoin::p2p::address::Address;
+use bitcoin::p2p::message::{NetworkMessage,RawNetworkMessage};
+use bitcoin::p2p::message_blockdata::Inventory;
+use bitcoin::p2p::message_network::VersionMessage;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::OutPoint;
+use bitcoin::TxOut;
+use bitcoin::Txid;
+use anyhow::{bail,Result};
+use tokio::io::{AsyncReadExt,AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::backend::{ChainBackend,TxStatus};
+use crate::types::RpcBestBlock;
+
+/// Protocol version which we announce, this is the same as pktd's
+const PROTOCOL_VERSION: u32 = 70013;
+
+/// Largest message we are willing to read, pktd will not send anything bigger
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
+/// How long to wait for any step of the conversation with the peer
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for the peer to ask for the transaction after we announce it
+const GETDATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Peer {
+    stream: TcpStream,
+    magic: Magic,
+}
+
+impl Peer {
+    async fn send(&mut self, msg: NetworkMessage) -> Result<()> {
+        let raw = RawNetworkMessage::new(self.magic, msg);
+        self.stream.write_all(&consensus::serialize(&raw)).await?;
+        Ok(())
+    }
+
+    /// Read the next message, None if it was a message which we are unable to parse.
+    async fn recv(&mut self) -> Result<Option<NetworkMessage>> {
+        let mut buf = vec![0_u8; 24];
+        timeout(STEP_TIMEOUT, self.stream.read_exact(&mut buf[..])).await??;
+        if buf[..4] != self.magic.to_bytes() {
+            bail!("Peer is on a different network, magic {}", hex::encode(&buf[..4]));
+        }
+        let len = u32::from_le_bytes([buf[16], buf[17], buf[18], buf[19]]) as usize;
+        if len > MAX_MESSAGE_SIZE {
+            bail!("Peer sent a message of {len} bytes, too big");
+        }
+        buf.resize(24 + len, 0);
+        timeout(STEP_TIMEOUT, self.stream.read_exact(&mut buf[24..])).await??;
+        match consensus::deserialize::<RawNetworkMessage>(&buf) {
+            Ok(msg) => Ok(Some(msg.payload().clone())),
+            Err(e) => {
+                eprintln!("Ignoring message which could not be parsed: {e}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Handle messages which need a reply no matter what we are doing,
+    /// return a reject message for our transaction as an error.
+    async fn handle_common(&mut self, msg: &NetworkMessage, txid: &Txid) -> Result<()> {
+        match msg {
+            NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(*nonce)).await,
+            NetworkMessage::Reject(rej) if rej.hash.to_byte_array() == txid.to_byte_array() => {
+                bail!("Transaction rejected by peer: {} ({:?})", rej.reason, rej.ccode);
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn connect(addr: SocketAddr, magic: Magic) -> Result<Self> {
+        let stream = timeout(STEP_TIMEOUT, TcpStream::connect(addr)).await??;
+        let mut peer = Peer{ stream, magic };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let mut version = VersionMessage::new(
+            ServiceFlags::NONE,
+            now.as_secs() as i64,
+            Address::new(&addr, ServiceFlags::NONE),
+            Address::new(&"0.0.0.0:0".parse()?, ServiceFlags::NONE),
+            now.subsec_nanos() as u64 ^ now.as_secs(),
+            format!("/PKT-Voter:{}/", env!("CARGO_PKG_VERSION")),
+            0,
+        );
+        version.version = PROTOCOL_VERSION;
+        version.relay = false;
+        peer.send(NetworkMessage::Version(version)).await?;
+
+        let (mut got_version, mut got_verack) = (false, false);
+        while !(got_version && got_verack) {
+            match peer.recv().await? {
+                Some(NetworkMessage::Version(v)) => {
+                    eprintln!("Connected to {addr} {} height {}", v.user_agent, v.start_height);
+                    got_version = true;
+                    peer.send(NetworkMessage::Verack).await?;
+                }
+                Some(NetworkMessage::Verack) => got_verack = true,
+                Some(NetworkMessage::Ping(nonce)) => peer.send(NetworkMessage::Pong(nonce)).await?,
+                _ => {}
+            }
+        }
+        Ok(peer)
+    }
+
+    async fn relay(&mut self, txn: &bitcoin::Transaction) -> Result<()> {
+        let txid = txn.txid();
+        self.send(NetworkMessage::Inv(vec![Inventory::Transaction(txid)])).await?;
+
+        // Wait for the peer to ask for it, if it doesn't then it might already have it,
+        // so we send it anyway.
+        let wait = async {
+            loop {
+                let Some(msg) = self.recv().await? else { continue };
+                self.handle_common(&msg, &txid).await?;
+                if let NetworkMessage::GetData(inv) = msg {
+                    if inv.iter().any(|i|match i {
+                        Inventory::Transaction(t) | Inventory::WitnessTransaction(t) => *t == txid,
+                        _ => false,
+                    }) {
+                        return Ok::<(), anyhow::Error>(());
+                    }
+                }
+            }
+        };
+        match timeout(GETDATA_TIMEOUT, wait).await {
+            Ok(res) => res?,
+            Err(_) => eprintln!("Peer did not ask for {txid}, sending it anyway"),
+        }
+        self.send(NetworkMessage::Tx(txn.clone())).await?;
+
+        // A reject would arrive before the pong, so once we have the pong we know it was accepted.
+        let nonce = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos() as u64;
+        self.send(NetworkMessage::Ping(nonce)).await?;
+        loop {
+            let Some(msg) = self.recv().await? else { continue };
+            self.handle_common(&msg, &txid).await?;
+            if let NetworkMessage::Pong(n) = msg {
+                if n == nonce {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Broadcasts transactions directly to pktd peers using the P2P protocol,
+/// everything else is looked up using another backend.
+pub struct P2p {
+    pub peers: Vec<String>,
+    pub magic: Magic,
+    pub chain: Arc<dyn ChainBackend>,
+}
+
+impl P2p {
+    async fn bcast_to(&self, peer: &str, txn: &bitcoin::Transaction) -> Result<()> {
+        let addr = match tokio::net::lookup_host(peer).await?.next() {
+            Some(addr) => addr,
+            None => bail!("Unable to resolve {peer}"),
+        };
+        let mut p = Peer::connect(addr, self.magic).await?;
+        p.relay(txn).await
+    }
+}
+
+#[async_trait]
+impl ChainBackend for P2p {
+    fn name(&self) -> String {
+        format!("p2p:{}+{}", self.peers.join(","), self.chain.name())
+    }
+
+    async fn get_utxos(&self, addr: &str, include_unconfirmed: bool) -> Result<Vec<(OutPoint,TxOut)>> {
+        self.chain.get_utxos(addr, include_unconfirmed).await
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<bitcoin::Transaction> {
+        self.chain.get_transaction(txid).await
+    }
+
+    async fn get_balance(&self, addr: &str) -> Result<Amount> {
+        self.chain.get_balance(addr).await
+    }
+
+    async fn get_tx_status(&self, txid: &Txid) -> Result<TxStatus> {
+        self.chain.get_tx_status(txid).await
+    }
+
+    async fn bcast_transaction(&self, txn: &bitcoin::Transaction) -> Result<()> {
+        let mut errors = Vec::new();
+        let mut accepted = 0;
+        for peer in &self.peers {
+            match self.bcast_to(peer, txn).await {
+                Ok(()) => {
+                    eprintln!("Transaction {} sent to {peer}", txn.txid());
+                    accepted += 1;
+                }
+                Err(e) => {
+                    eprintln!("Failed to send transaction to {peer}: {e}");
+                    errors.push(format!("{peer}: {e}"));
+                }
+            }
+        }
+        if accepted == 0 {
+            bail!("No peer accepted the transaction: {}", errors.join(", "));
+        }
+        Ok(())
+    }
+
+    async fn get_tip(&self) -> Result<RpcBestBlock> {
+        self.chain.get_tip().await
+    }
+
+    async fn estimate_fee_rate(&self) -> Result<Option<FeeRate>> {
+        self.chain.estimate_fee_rate().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::p2p::message_network::{Reject,RejectReason};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::vote::Vote;
+
+
+    fn test_txn() -> bitcoin::Transaction {
+        bitcoin::Transaction{
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn{
+                previous_output: OutPoint::new(Txid::from_byte_array([1; 32]), 0),
+                ..Default::default()
+            }],
+            output: vec![Vote::default().encode()],
+        }
+    }
+
+    async fn expect(peer: &mut Peer) -> NetworkMessage {
+        loop {
+            if let Some(msg) = peer.recv().await.unwrap() {
+                return msg;
+            }
+        }
+    }
+
+    /// A pktd which completes the handshake, asks for the transaction and then either
+    /// rejects it or answers the ping. Returns the transaction which it was sent.
+    async fn fake_peer(listener: TcpListener, reject: bool) -> bitcoin::Transaction {
+        let (stream, addr) = listener.accept().await.unwrap();
+        let mut peer = Peer{ stream, magic: crate::network::MAINNET.magic() };
+
+        let NetworkMessage::Version(v) = expect(&mut peer).await else { panic!("expected version") };
+        assert!(v.user_agent.starts_with("/PKT-Voter:"));
+        let mut version = VersionMessage::new(
+            ServiceFlags::NETWORK,
+            0,
+            Address::new(&addr, ServiceFlags::NONE),
+            Address::new(&addr, ServiceFlags::NONE),
+            1,
+            "/pktd:fake/".into(),
+            100,
+        );
+        version.version = PROTOCOL_VERSION;
+        peer.send(NetworkMessage::Version(version)).await.unwrap();
+        peer.send(NetworkMessage::Verack).await.unwrap();
+        assert_eq!(expect(&mut peer).await, NetworkMessage::Verack);
+
+        let NetworkMessage::Inv(inv) = expect(&mut peer).await else { panic!("expected inv") };
+        peer.send(NetworkMessage::GetData(inv)).await.unwrap();
+        let NetworkMessage::Tx(txn) = expect(&mut peer).await else { panic!("expected tx") };
+
+        if reject {
+            peer.send(NetworkMessage::Reject(Reject{
+                message: "tx".into(),
+                ccode: RejectReason::Fee,
+                reason: "insufficient fee".into(),
+                hash: txn.txid().to_raw_hash(),
+            })).await.unwrap();
+        } else {
+            let NetworkMessage::Ping(nonce) = expect(&mut peer).await else { panic!("expected ping") };
+            peer.send(NetworkMessage::Pong(nonce)).await.unwrap();
+        }
+        txn
+    }
+
+    async fn start(reject: bool) -> (P2p, tokio::task::JoinHandle<bitcoin::Transaction>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let p2p = P2p{
+            peers: vec![addr.to_string()],
+            magic: crate::network::MAINNET.magic(),
+            chain: Arc::new(MockBackend::default()),
+        };
+        (p2p, tokio::spawn(fake_peer(listener, reject)))
+    }
+
+    #[tokio::test]
+    async fn bcast_accepted() {
+        let (p2p, peer) = start(false).await;
+        let txn = test_txn();
+        p2p.bcast_transaction(&txn).await.unwrap();
+        assert_eq!(peer.await.unwrap(), txn);
+    }
+
+    #[tokio::test]
+    async fn bcast_rejected() {
+        let (p2p, peer) = start(true).await;
+        let txn = test_txn();
+        let err = p2p.bcast_transaction(&txn).await.unwrap_err().to_string();
+        assert!(err.contains("Transaction rejected by peer: insufficient fee"), "{err}");
+        assert_eq!(peer.await.unwrap(), txn);
+    }
+}
@@ -0,0 +1,274 @@
+use std::str::FromStr;
+
+use bitcoin::bip32::{ChildNumber,DerivationPath,KeySource,Xpriv};
+use bitcoin::key::Secp256k1;
+use bitcoin::key::secp256k1::All;
+use bitcoin::Amount;
+use argon2::{Algorithm,Argon2,Params,Version};
+use blake2::{Blake2b,Digest};
+use blake2::digest::consts::U32;
+use anyhow::{anyhow,bail,Result};
+
+use crate::backend::ChainBackend;
+use crate::wallet::{self,AddrInfo};
+
+/// Account which is scanned if no other is given, 390 is the SLIP-44 coin type of PKT
+pub const DEFAULT_ACCOUNT_PATH: &str = "m/84'/390'/0'";
+
+/// Account of a pktwallet seed, pktwallet uses coin type 0
+pub const PKTWALLET_ACCOUNT_PATH: &str = "m/84'/0'/0'";
+
+/// Salt of the key which encrypts a pktwallet seed
+const PKTWALLET_SALT: &[u8] = b"pktwallet seed 0";
+
+/// Stop scanning a chain after this many empty addresses in a row (the BIP44 gap limit)
+const GAP_LIMIT: u32 = 20;
+
+/// An address of the wallet which has coins
+pub struct SeedAddr {
+    pub path: DerivationPath,
+    pub balance: Amount,
+    pub ai: AddrInfo,
+}
+
+/// Decode the 15 words of a pktwallet seed, None if they are not one. The words are a 165 bit
+/// number: version (4 bits, 0), encrypted (1 bit), checksum (1 byte), birthday (2 bytes) and the
+/// 17 byte seed. An encrypted seed is xored with a key derived from the passphrase with Argon2id.
+/// The checksum is the first byte of the blake2b-256 of the decrypted seed with the checksum and
+/// the encrypted bit cleared.
+fn pktwallet_seed(words: &[&str], passphrase: &str) -> Result<Option<[u8; 17]>> {
+    if words.len() != 15 {
+        return Ok(None);
+    }
+    // 3 bits of padding in front to make 21 bytes
+    let mut bytes = [0u8; 21];
+    let mut bit = 3;
+    for w in words {
+        let Some(idx) = bip39::Language::English.find_word(w) else { return Ok(None) };
+        for i in (0..11).rev() {
+            if idx >> i & 1 == 1 {
+                bytes[bit / 8] |= 0x80 >> (bit % 8);
+            }
+            bit += 1;
+        }
+    }
+    if bytes[0] >> 1 != 0 {
+        return Ok(None);
+    }
+    let encrypted = bytes[0] & 1 == 1;
+    if encrypted {
+        if passphrase.is_empty() {
+            bail!("This pktwallet seed is encrypted, enter its passphrase");
+        }
+        let params = Params::new(64 * 1024, 1, 4, Some(17))
+            .map_err(|e|anyhow!("Invalid key derivation parameters: {e}"))?;
+        let mut key = [0u8; 17];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), PKTWALLET_SALT, &mut key)
+            .map_err(|e|anyhow!("Unable to derive key from passphrase: {e}"))?;
+        for (b, k) in bytes[4..].iter_mut().zip(key) {
+            *b ^= k;
+        }
+    }
+    let checksum = bytes[1];
+    bytes[0] = 0;
+    bytes[1] = 0;
+    if Blake2b::<U32>::digest(bytes)[0] != checksum {
+        if encrypted {
+            bail!("Wrong passphrase for this pktwallet seed");
+        }
+        return Ok(None);
+    }
+    Ok(Some(bytes[4..].try_into().unwrap()))
+}
+
+/// The account key of a BIP39 or pktwallet seed phrase, along with the master fingerprint and
+/// the path of the account. If no account is given, the default one of the kind of seed is used.
+pub fn account_key(
+    words: &str,
+    passphrase: &str,
+    account_path: Option<&str>,
+    secp: &Secp256k1<All>,
+) -> Result<(Xpriv, KeySource)> {
+    let words = words.split_whitespace().collect::<Vec<_>>();
+    let (seed, default_path) = match (pktwallet_seed(&words, passphrase), bip39::Mnemonic::parse(words.join(" "))) {
+        (Ok(Some(seed)), _) => (seed.to_vec(), PKTWALLET_ACCOUNT_PATH),
+        (_, Ok(m)) => (m.to_seed(passphrase).to_vec(), DEFAULT_ACCOUNT_PATH),
+        (Err(e), Err(_)) => return Err(e),
+        (Ok(None), Err(e)) => bail!("Invalid seed phrase: {e}"),
+    };
+    let account_path = account_path.unwrap_or(default_path);
+    let path = match DerivationPath::from_str(account_path.trim()) {
+        Ok(p) => p,
+        Err(e) => bail!("Invalid derivation path {account_path}: {e}"),
+    };
+    let master = Xpriv::new_master(bitcoin::Network::Bitcoin, &seed)?;
+    Ok((master.derive_priv(secp, &path)?, (master.fingerprint(secp), path)))
+}
+
+/// The addresses of a key of the wallet with its origin. The purpose of the path chooses the kind
+/// of address, p2pkh for BIP44 and p2wpkh for BIP84, for any other purpose both are given.
+pub fn key_addresses(key: &Xpriv, origin: KeySource, secp: &Secp256k1<All>) -> Vec<AddrInfo> {
+    let purpose = origin.1.into_iter().next().copied();
+    let mut addrs = wallet::key_to_addresses(key.to_priv(), secp);
+    match purpose {
+        Some(ChildNumber::Hardened{ index: 44 }) => addrs.retain(|ai|ai.change_script.is_p2pkh()),
+        Some(ChildNumber::Hardened{ index: 84 }) => addrs.retain(|ai|ai.change_script.is_p2wpkh()),
+        _ => {}
+    }
+    for ai in &mut addrs {
+        ai.key_origin = Some(origin.clone());
+    }
+    addrs
+}
+
+/// The addresses at index of chain (0 for receive, 1 for change) of the account
+pub fn derive_address(
+    account: &Xpriv,
+    account_origin: &KeySource,
+    chain: u32,
+    index: u32,
+    secp: &Secp256k1<All>,
+) -> Result<(DerivationPath, Vec<AddrInfo>)> {
+    let rel = [ChildNumber::from_normal_idx(chain)?, ChildNumber::from_normal_idx(index)?];
+    let key = account.derive_priv(secp, &rel)?;
+    let path = account_origin.1.extend(rel);
+    Ok((path.clone(), key_addresses(&key, (account_origin.0, path), secp)))
+}
+
+/// Scan the receive and change addresses of the account and return the ones which have coins.
+pub async fn discover(
+    backend: &dyn ChainBackend,
+    account: &Xpriv,
+    account_origin: &KeySource,
+    secp: &Secp256k1<All>,
+) -> Result<Vec<SeedAddr>> {
+    let mut out = Vec::new();
+    for chain in [0, 1] {
+        let mut empty = 0;
+        let mut index = 0;
+        while empty < GAP_LIMIT {
+            let (path, addrs) = derive_address(account, account_origin, chain, index, secp)?;
+            empty += 1;
+            for ai in addrs {
+                let balance = backend.get_balance(&ai.addr_str).await?;
+                eprintln!("{path} {} {}", ai.addr_str, wallet::amount_pkt(&balance));
+                if balance > Amount::ZERO {
+                    empty = 0;
+                    out.push(SeedAddr{ path: path.clone(), balance, ai });
+                }
+            }
+            index += 1;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The words of a pktwallet seed, the inverse of pktwallet_seed
+    fn pktwallet_words(seed: [u8; 17], birthday: u16, passphrase: &str) -> String {
+        let mut bytes = [0u8; 21];
+        bytes[2..4].copy_from_slice(&birthday.to_le_bytes());
+        bytes[4..].copy_from_slice(&seed);
+        let checksum = Blake2b::<U32>::digest(bytes)[0];
+        if !passphrase.is_empty() {
+            let params = Params::new(64 * 1024, 1, 4, Some(17)).unwrap();
+            let mut key = [0u8; 17];
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(passphrase.as_bytes(), PKTWALLET_SALT, &mut key)
+                .unwrap();
+            for (b, k) in bytes[4..].iter_mut().zip(key) {
+                *b ^= k;
+            }
+            bytes[0] = 1;
+        }
+        bytes[1] = checksum;
+        let list = bip39::Language::English.word_list();
+        (0..15).map(|w| {
+            let idx = (0..11).fold(0, |idx, i| {
+                let bit = 3 + w * 11 + i;
+                idx << 1 | (bytes[bit / 8] >> (7 - bit % 8) & 1) as usize
+            });
+            list[idx]
+        }).collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn bip39_uses_the_pkt_account() {
+        let secp = Secp256k1::new();
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let (_, origin) = account_key(words, "", None, &secp).unwrap();
+        assert_eq!(origin.1.to_string(), "m/84'/390'/0'");
+        assert!(account_key("abandon abandon", "", None, &secp).is_err());
+    }
+
+    #[test]
+    fn pktwallet_seed_decodes() {
+        let secp = Secp256k1::new();
+        let words = pktwallet_words([7; 17], 1000, "");
+        assert_eq!(words.split(' ').count(), 15);
+        let (account, origin) = account_key(&words, "", None, &secp).unwrap();
+        assert_eq!(origin.1.to_string(), PKTWALLET_ACCOUNT_PATH);
+        let master = Xpriv::new_master(bitcoin::Network::Bitcoin, &[7; 17]).unwrap();
+        assert_eq!(account, master.derive_priv(&secp, &origin.1).unwrap());
+
+        // One word changed breaks the checksum
+        let typo = words.replacen(words.split(' ').next().unwrap(), "zoo", 1);
+        assert!(account_key(&typo, "", None, &secp).is_err());
+    }
+
+    #[test]
+    fn encrypted_pktwallet_seed_needs_the_passphrase() {
+        let secp = Secp256k1::new();
+        let words = pktwallet_words([9; 17], 1000, "secret");
+        let master = Xpriv::new_master(bitcoin::Network::Bitcoin, &[9; 17]).unwrap();
+        let (account, origin) = account_key(&words, "secret", None, &secp).unwrap();
+        assert_eq!(account, master.derive_priv(&secp, &origin.1).unwrap());
+        let err = account_key(&words, "", None, &secp).unwrap_err().to_string();
+        assert!(err.contains("encrypted"), "{err}");
+        let err = account_key(&words, "wrong", None, &secp).unwrap_err().to_string();
+        assert!(err.contains("Wrong passphrase"), "{err}");
+    }
+
+    #[test]
+    fn purpose_chooses_the_address_type() {
+        let secp = Secp256k1::new();
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        for (path, p2pkh, p2wpkh) in [("m/44'/390'/0'", true, false), ("m/84'/390'/0'", false, true), ("m/0'", true, true)] {
+            let (account, origin) = account_key(words, "", Some(path), &secp).unwrap();
+            let (_, addrs) = derive_address(&account, &origin, 0, 0, &secp).unwrap();
+            assert_eq!(addrs.iter().any(|ai|ai.change_script.is_p2pkh()), p2pkh, "{path}");
+            assert_eq!(addrs.iter().any(|ai|ai.change_script.is_p2wpkh()), p2wpkh, "{path}");
+            assert_eq!(addrs.len(), p2pkh as usize + p2wpkh as usize, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn discover_finds_the_funded_addresses() {
+        use crate::backend::mock::MockBackend;
+        use bitcoin::{TxIn,TxOut};
+        let secp = Secp256k1::new();
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let (account, origin) = account_key(words, "", Some("m/44'/390'/0'"), &secp).unwrap();
+        let backend = MockBackend::default();
+        let mut want = Vec::new();
+        for (chain, index) in [(0, 2), (0, 21), (1, 0)] {
+            let (path, addrs) = derive_address(&account, &origin, chain, index, &secp).unwrap();
+            let ai = &addrs[0];
+            backend.add(bitcoin::Transaction{
+                version: bitcoin::transaction::Version::TWO,
+                lock_time: bitcoin::absolute::LockTime::ZERO,
+                input: vec![TxIn::default()],
+                output: vec![TxOut{ value: Amount::from_sat(1000 + index as u64), script_pubkey: ai.change_script.clone() }],
+            }, Some(1));
+            want.push((path.to_string(), ai.addr_str.clone()));
+        }
+        let found = discover(&backend, &account, &origin, &secp).await.unwrap();
+        let got: Vec<_> = found.iter().map(|sa|(sa.path.to_string(), sa.ai.addr_str.clone())).collect();
+        assert_eq!(got, want);
+        assert!(found.iter().all(|sa|sa.ai.change_script.is_p2pkh()));
+    }
+}
@@ -0,0 +1,128 @@
+use bitcoin::script::{Instruction,PushBytes};
+use bitcoin::opcodes::all::OP_RETURN;
+use bitcoin::Amount;
+use bitcoin::Script;
+use bitcoin::ScriptBuf;
+use bitcoin::TxOut;
+use anyhow::{bail,Result};
+
+/// First byte of the vote data, the rest is the script of the address voted for
+const VOTE: u8 = 0x00;
+const VOTE_CANDIDATE: u8 = 0x01;
+
+/// A vote, which is carried by a zero value OP_RETURN output
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Vote {
+    /// Script of the address voted for, None to vote for nobody
+    pub vote_for: Option<ScriptBuf>,
+    /// Whether the voter declares themselves a candidate
+    pub is_candidate: bool,
+}
+
+impl Vote {
+    /// The output which carries the vote
+    pub fn encode(&self) -> TxOut {
+        let mut data = vec![if self.is_candidate { VOTE_CANDIDATE } else { VOTE }];
+        if let Some(vf) = &self.vote_for {
+            data.extend_from_slice(vf.as_bytes());
+        }
+        let pb: &PushBytes = data[..].try_into().unwrap();
+        TxOut{
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return(pb),
+        }
+    }
+
+    /// Decode the script of a vote output, which must be OP_RETURN followed by a single push.
+    pub fn decode(script: &Script) -> Result<Vote> {
+        let mut ins = script.instructions();
+        if !matches!(ins.next(), Some(Ok(Instruction::Op(OP_RETURN)))) {
+            bail!("Not a vote, the script does not begin with OP_RETURN");
+        }
+        let data = match ins.next() {
+            Some(Ok(Instruction::PushBytes(pb))) => pb.as_bytes(),
+            Some(Ok(Instruction::Op(op))) => bail!("Not a vote, {op} rather than a push after OP_RETURN"),
+            Some(Err(e)) => bail!("Not a vote, invalid push: {e}"),
+            None => bail!("Not a vote, nothing after OP_RETURN"),
+        };
+        if ins.next().is_some() {
+            bail!("Not a vote, more than one push after OP_RETURN");
+        }
+        let is_candidate = match data.first() {
+            Some(&VOTE) => false,
+            Some(&VOTE_CANDIDATE) => true,
+            Some(b) => bail!("Not a vote, the data begins with {b:#04x}"),
+            None => bail!("Not a vote, the data is empty"),
+        };
+        let vote_for = if data.len() > 1 { Some(ScriptBuf::from_bytes(data[1..].to_vec())) } else { None };
+        Ok(Vote{ vote_for, is_candidate })
+    }
+
+    /// Decode a vote output, checking that it does not burn any coins.
+    pub fn from_txout(txout: &TxOut) -> Result<Vote> {
+        if txout.value != Amount::ZERO {
+            bail!("Not a vote, the output has a value of {} units", txout.value.to_sat());
+        }
+        Self::decode(&txout.script_pubkey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::opcodes::all::OP_PUSHNUM_1;
+    use bitcoin::script::Builder;
+    use bitcoin::WPubkeyHash;
+
+    use super::*;
+
+    fn op_return(data: &[u8]) -> ScriptBuf {
+        let pb: &PushBytes = data.try_into().unwrap();
+        ScriptBuf::new_op_return(pb)
+    }
+
+    #[test]
+    fn round_trip() {
+        let vote_for = Some(ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(b"pkt")));
+        for vote in [
+            Vote{ vote_for: vote_for.clone(), is_candidate: false },
+            Vote{ vote_for, is_candidate: true },
+            Vote{ vote_for: None, is_candidate: false },
+            Vote{ vote_for: None, is_candidate: true },
+        ] {
+            let txout = vote.encode();
+            assert_eq!(txout.value, Amount::ZERO);
+            assert_eq!(Vote::from_txout(&txout).unwrap(), vote);
+        }
+    }
+
+    #[test]
+    fn nonzero_value() {
+        let mut txout = Vote::default().encode();
+        txout.value = Amount::from_sat(1);
+        assert!(Vote::from_txout(&txout).unwrap_err().to_string().contains("value"));
+    }
+
+    #[test]
+    fn two_pushes() {
+        let script = Builder::new().push_opcode(OP_RETURN).push_slice([VOTE]).push_slice([0x51]).into_script();
+        assert!(Vote::decode(&script).unwrap_err().to_string().contains("more than one push"));
+        let script = Builder::new().push_opcode(OP_RETURN).push_opcode(OP_PUSHNUM_1).into_script();
+        assert!(Vote::decode(&script).unwrap_err().to_string().contains("rather than a push"));
+    }
+
+    #[test]
+    fn empty_payload() {
+        assert!(Vote::decode(&op_return(&[])).unwrap_err().to_string().contains("empty"));
+        let script = Builder::new().push_opcode(OP_RETURN).into_script();
+        assert!(Vote::decode(&script).unwrap_err().to_string().contains("nothing after OP_RETURN"));
+    }
+
+    #[test]
+    fn unknown_leading_byte() {
+        let err = Vote::decode(&op_return(&[0x02, 0x00, 0x14])).unwrap_err().to_string();
+        assert!(err.contains("0x02"), "{err}");
+        let err = Vote::decode(&ScriptBuf::new_p2wpkh(&WPubkeyHash::hash(b"pkt"))).unwrap_err().to_string();
+        assert!(err.contains("does not begin with OP_RETURN"), "{err}");
+    }
+}
@@ -0,0 +1,1190 @@
+use std::str::FromStr;
+use std::collections::BTreeMap;
+
+use bitcoin::bip32::DerivationPath;
+use bitcoin::bip32::Fingerprint;
+use bitcoin::bip32::KeySource;
+use bitcoin::consensus::encode;
+use bitcoin::key::Secp256k1;
+use bitcoin::key::secp256k1::{self, All};
+use bitcoin::psbt;
+use bitcoin::psbt::Input;
+use bitcoin::psbt::PsbtSighashType;
+use bitcoin::opcodes::all::{OP_PUSHNUM_1,OP_PUSHNUM_16};
+use bitcoin::script::{Instruction,PushBytes};
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::OutPoint;
+use bitcoin::Script;
+use bitcoin::ScriptBuf;
+use bitcoin::Sequence;
+use bitcoin::TxIn;
+use bitcoin::TxOut;
+use bitcoin::Witness;
+use bitcoin::hashes::Hash;
+use miniscript::psbt::PsbtExt;
+use miniscript::{DefiniteDescriptorKey,Descriptor};
+
+use anyhow::{bail,Result};
+
+use crate::address::PktAddress;
+use crate::backend::{ChainBackend,TxStatus};
+use crate::network;
+use crate::vote::Vote;
+
+#[derive(Clone)]
+pub struct AddrInfo {
+    /// None if the address is watch-only
+    pub private_key: Option<bitcoin::PrivateKey>,
+    /// None if the address is watch-only
+    pub public_key: Option<bitcoin::PublicKey>,
+    pub change_script: ScriptBuf,
+    pub addr_str: String,
+    /// If the address is defined by an output descriptor (e.g. a multisig),
+    /// the descriptor at the index of the address
+    pub descriptor: Option<Descriptor<DefiniteDescriptorKey>>,
+    /// Master fingerprint and full derivation path of the key, if it was derived from an
+    /// extended key (e.g. a seed)
+    pub key_origin: Option<KeySource>,
+}
+impl AddrInfo {
+    pub fn is_watch_only(&self) -> bool {
+        self.private_key.is_none()
+    }
+}
+
+/// Fee rate used when neither the user nor the backend gives one, in units per vbyte.
+/// For a vote with one input this is about the 500 units which we used to pay.
+pub const DEFAULT_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(4);
+
+/// A replacement must pay at least this much more than the transaction it replaces (BIP125)
+const MIN_BUMP_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+
+
+/// All of the txouts of the address
+pub async fn get_address_utxos(
+    backend: &dyn ChainBackend,
+    addr: &str,
+) -> Result<Vec<(OutPoint,TxOut)>> {
+    let utxos = backend.get_utxos(addr, false).await?;
+    if utxos.is_empty() {
+        bail!("No unspent txouts found for address {addr}");
+    }
+    Ok(utxos)
+}
+
+/// Parse a fee rate in units per vbyte, fractions are allowed.
+pub fn parse_fee_rate(s: &str) -> Result<FeeRate> {
+    let Ok(rate) = s.trim().parse::<f64>() else {
+        bail!("Fee rate must be a number of units per vbyte");
+    };
+    if rate.is_nan() || rate <= 0.0 {
+        bail!("Fee rate must be greater than zero");
+    }
+    Ok(FeeRate::from_sat_per_kwu((rate * 250.0).ceil() as u64))
+}
+
+/// Fee rate in units per vbyte
+pub fn fee_rate_per_vb(rate: FeeRate) -> f64 {
+    rate.to_sat_per_kwu() as f64 / 250.0
+}
+
+/// The fee rate given by the user, or else the one estimated by the backend, or else the default.
+pub async fn choose_fee_rate(backend: &dyn ChainBackend, user: Option<FeeRate>) -> FeeRate {
+    if let Some(rate) = user {
+        return rate;
+    }
+    match backend.estimate_fee_rate().await {
+        Ok(Some(rate)) => rate,
+        Ok(None) => DEFAULT_FEE_RATE,
+        Err(e) => {
+            eprintln!("Unable to estimate fee rate, using the default: {e}");
+            DEFAULT_FEE_RATE
+        }
+    }
+}
+
+/// Virtual size of the transaction once it is signed, inputs which are not yet signed
+/// are filled with dummy signatures.
+pub fn estimate_vsize(tx: &psbt::Psbt) -> u64 {
+    let mut unsigned = tx.unsigned_tx.clone();
+    let prevouts = psbt_prevouts(tx);
+    for ((txin, input), (_, prevout)) in unsigned.input.iter_mut().zip(tx.inputs.iter()).zip(prevouts) {
+        if input.final_script_witness.is_some() || input.final_script_sig.is_some() {
+            txin.witness = input.final_script_witness.clone().unwrap_or_default();
+            txin.script_sig = input.final_script_sig.clone().unwrap_or_default();
+        } else {
+            (txin.script_sig, txin.witness) = dummy_satisfaction(input, prevout.as_ref());
+        }
+    }
+    unsigned.vsize() as u64
+}
+
+/// A scriptSig and witness of the same size as the ones which will spend the input, a signature
+/// with its sighash byte is 72 bytes and public keys in p2pkh are assumed to be uncompressed,
+/// which is the bigger. An input whose prevout is unknown is assumed to be p2wpkh.
+fn dummy_satisfaction(input: &Input, prevout: Option<&TxOut>) -> (ScriptBuf, Witness) {
+    let sig = vec![0_u8; 72];
+    let p2wpkh = Witness::from_slice(&[sig.clone(), vec![0_u8; 33]]);
+    let multisig = |script: &Script| {
+        let mut items = vec![Vec::new()];
+        items.extend(std::iter::repeat_n(sig.clone(), script_sigs_needed(script)));
+        items.push(script.to_bytes());
+        items
+    };
+    let Some(prevout) = prevout else {
+        return (ScriptBuf::new(), p2wpkh);
+    };
+    let spk = &prevout.script_pubkey;
+    if spk.is_p2pkh() {
+        (push_all(&[sig, vec![0_u8; 65]]), Witness::new())
+    } else if spk.is_p2wsh() {
+        let ws = input.witness_script.clone().unwrap_or_default();
+        (ScriptBuf::new(), Witness::from_slice(&multisig(&ws)))
+    } else if spk.is_p2sh() {
+        // Without a redeem script, p2sh is assumed to be p2sh-p2wpkh, otherwise p2sh-p2wsh
+        let redeem = input.redeem_script.clone().unwrap_or_else(||ScriptBuf::new_p2wpkh(&bitcoin::WPubkeyHash::all_zeros()));
+        if redeem.is_p2wpkh() {
+            (push_all(&[redeem.to_bytes()]), p2wpkh)
+        } else {
+            let ws = input.witness_script.clone().unwrap_or_default();
+            (push_all(&[redeem.to_bytes()]), Witness::from_slice(&multisig(&ws)))
+        }
+    } else {
+        (ScriptBuf::new(), p2wpkh)
+    }
+}
+
+/// Number of signatures which a script needs, for multi() this is the threshold,
+/// for anything else every key in the script is assumed to sign.
+pub fn script_sigs_needed(script: &Script) -> usize {
+    let mut keys = 0;
+    for (i, ins) in script.instructions().enumerate() {
+        match ins {
+            Ok(Instruction::Op(op)) if i == 0 && (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&op.to_u8()) => {
+                return (op.to_u8() - OP_PUSHNUM_1.to_u8() + 1) as usize;
+            }
+            Ok(Instruction::PushBytes(pb)) if pb.len() == 33 || pb.len() == 65 => keys += 1,
+            _ => {}
+        }
+    }
+    keys
+}
+
+/// A script which pushes each of the items
+fn push_all(items: &[Vec<u8>]) -> ScriptBuf {
+    let mut b = bitcoin::script::Builder::new();
+    for item in items {
+        let pb: &PushBytes = item[..].try_into().unwrap();
+        b = b.push_slice(pb);
+    }
+    b.into_script()
+}
+
+/// Size of an input spending from the address once it is signed, in vbytes
+fn input_vsize(ai: &AddrInfo) -> Result<u64> {
+    let prevout = TxOut{ value: Amount::ZERO, script_pubkey: ai.change_script.clone() };
+    let tx = spend_psbt(ai, &[(OutPoint::null(), prevout.clone())], Vec::new())?;
+    let (script_sig, witness) = dummy_satisfaction(&tx.inputs[0], Some(&prevout));
+    let txin = TxIn{ script_sig, witness, ..Default::default() };
+    Ok(txin.segwit_weight().to_vbytes_ceil())
+}
+
+pub fn amount_pkt(amt: &Amount) -> f64 {
+    amt.to_sat() as f64 / network::current().units_per_coin as f64
+}
+
+pub fn script_from_address(addr: &str) -> Result<bitcoin::ScriptBuf> {
+    Ok(PktAddress::from_str(addr)?.script_pubkey())
+}
+
+/// The address which a key should normally use, p2wpkh unless the key is uncompressed.
+pub fn wif_key_to_address(wif_key: &str, secp: &Secp256k1<All>) -> Result<AddrInfo> {
+    let mut addrs = wif_key_to_addresses(wif_key, secp)?;
+    Ok(addrs.remove(0))
+}
+
+/// Every address which a key is able to spend from, p2wpkh first and then p2pkh,
+/// older wallets kept their coins on the p2pkh address.
+pub fn wif_key_to_addresses(wif_key: &str, secp: &Secp256k1<All>) -> Result<Vec<AddrInfo>> {
+    Ok(key_to_addresses(decode_wif(wif_key)?, secp))
+}
+
+/// Version bytes of the private keys of some other chains, to say why a key is not accepted.
+/// Bitcoin testnet's 0xef is not here because PKT keys use it on every network, so a key does
+/// not tell which network it is for and a Bitcoin testnet key can't be told from a PKT one.
+const OTHER_WIF_PREFIXES: &[(u8, &str)] = &[
+    (0x80, "Bitcoin"),
+    (0xb0, "Litecoin"),
+    (0x9e, "Dogecoin"),
+    (0xcc, "Dash"),
+];
+
+/// Decode a private key in WIF, checking that it is a PKT key
+pub fn decode_wif(wif_key: &str) -> Result<bitcoin::PrivateKey> {
+    let data = match bitcoin::base58::decode_check(wif_key.trim()) {
+        Ok(data) => data,
+        Err(e) => bail!("Not a WIF private key: {e}"),
+    };
+    if data.is_empty() {
+        bail!("Not a WIF private key: it is empty");
+    }
+    let wif_prefix = network::current().wif_prefix;
+    if data[0] != wif_prefix {
+        if let Some((_, chain)) = OTHER_WIF_PREFIXES.iter().find(|(v, _)|*v == data[0]) {
+            bail!("This is a {chain} private key, not a PKT one");
+        }
+        bail!("This is not a PKT private key, its version byte is {:#04x} rather than {wif_prefix:#04x}", data[0]);
+    }
+    let compressed = match data.len() {
+        33 => false,
+        34 if data[33] == 0x01 => true,
+        34 => bail!("Invalid private key, the compression flag is {:#04x} rather than 0x01", data[33]),
+        n => bail!("Invalid private key size, {} bytes rather than 32 or 33", n - 1),
+    };
+    let inner = match secp256k1::SecretKey::from_slice(&data[1..33]) {
+        Ok(inner) => inner,
+        Err(e) => bail!("Invalid private key: {e}"),
+    };
+    Ok(bitcoin::PrivateKey {
+        compressed,
+        network: bitcoin::Network::Bitcoin,
+        inner,
+    })
+}
+
+/// WIF encoding of a private key, as exported by pktwallet
+pub fn encode_wif(sk: &bitcoin::PrivateKey) -> String {
+    let mut data = vec![network::current().wif_prefix];
+    data.extend_from_slice(&sk.inner.secret_bytes());
+    if sk.compressed {
+        data.push(1);
+    }
+    bitcoin::base58::encode_check(&data)
+}
+
+pub fn key_to_address(sk: bitcoin::PrivateKey, secp: &Secp256k1<All>) -> Result<AddrInfo> {
+    let pk = sk.public_key(secp);
+    // println!("Made public key: {pk:?}");
+    if let Some(wpkh) = pk.wpubkey_hash() {
+        let addr = PktAddress::P2wpkh(wpkh);
+        Ok(AddrInfo{
+            private_key: Some(sk),
+            public_key: Some(pk),
+            addr_str: addr.to_string(),
+            change_script: addr.script_pubkey(),
+            descriptor: None,
+            key_origin: None,
+        })
+    } else {
+        bail!("Key is not valid p2wkph");
+    }
+}
+
+/// Both the p2wpkh and the p2pkh address of a key, an uncompressed key only has a p2pkh address.
+pub fn key_to_addresses(sk: bitcoin::PrivateKey, secp: &Secp256k1<All>) -> Vec<AddrInfo> {
+    let pk = sk.public_key(secp);
+    let mut out = Vec::new();
+    let addrs = [pk.wpubkey_hash().map(PktAddress::P2wpkh), Some(PktAddress::P2pkh(pk.pubkey_hash()))];
+    for addr in addrs.into_iter().flatten() {
+        out.push(AddrInfo{
+            private_key: Some(sk),
+            public_key: Some(pk),
+            addr_str: addr.to_string(),
+            change_script: addr.script_pubkey(),
+            descriptor: None,
+            key_origin: None,
+        });
+    }
+    out
+}
+
+/// Scripts of the outputs which a public key is able to spend
+fn key_scripts(pk: &bitcoin::PublicKey) -> Vec<ScriptBuf> {
+    let mut out = vec![ScriptBuf::new_p2pkh(&pk.pubkey_hash())];
+    if let Some(wpkh) = pk.wpubkey_hash() {
+        out.push(ScriptBuf::new_p2wpkh(&wpkh));
+    }
+    out
+}
+
+/// Of the addresses of a key, the one with the most coins, or the first one if none has any.
+pub async fn choose_funded(backend: &dyn ChainBackend, mut addrs: Vec<AddrInfo>) -> Result<AddrInfo> {
+    if addrs.len() < 2 {
+        let Some(ai) = addrs.pop() else {
+            bail!("No address to choose from");
+        };
+        return Ok(ai);
+    }
+    let mut best = (0, Amount::ZERO);
+    for (i, ai) in addrs.iter().enumerate() {
+        let balance = backend.get_balance(&ai.addr_str).await?;
+        eprintln!("Address {} has {}", ai.addr_str, amount_pkt(&balance));
+        if balance > best.1 {
+            best = (i, balance);
+        }
+    }
+    Ok(addrs.swap_remove(best.0))
+}
+
+/// Create a watch-only AddrInfo which can be used to build an unsigned vote, but not sign it.
+pub fn watch_only_address(addr: &str) -> Result<AddrInfo> {
+    let addr = PktAddress::from_str(addr)?;
+    Ok(AddrInfo{
+        private_key: None,
+        public_key: None,
+        change_script: addr.script_pubkey(),
+        addr_str: addr.to_string(),
+        descriptor: None,
+        key_origin: None,
+    })
+}
+
+pub fn make_vote(
+    ai: &AddrInfo,
+    utxos: &[(OutPoint,TxOut)],
+    consolidate: bool,
+    fee_rate: FeeRate,
+    vote: &Vote,
+) -> Result<psbt::Psbt> {
+    let vote = vote.encode();
+
+    // A txout which can't even pay for the cost of spending it is not worth spending.
+    let input_fee = fee_rate.fee_vb(input_vsize(ai)?).unwrap_or(Amount::MAX);
+    let mut usable: Vec<_> = utxos.iter().filter(|(_, txout)|txout.value > input_fee).cloned().collect();
+    if usable.is_empty() {
+        bail!("No txout of address {} is large enough to pay the fee", ai.addr_str);
+    }
+
+    // Either spend everything (consolidate) or the biggest ones until there is enough to pay the fee.
+    usable.sort_by_key(|(_, txout)|std::cmp::Reverse(txout.value));
+    let first = if consolidate { usable.len() } else { 1 };
+    for n in first..=usable.len() {
+        let mut tx = spend_psbt(ai, &usable[..n], vec![vote.clone()])?;
+        let total: Amount = usable[..n].iter().map(|(_, txout)|txout.value).sum();
+        let fee = fee_rate.fee_vb(estimate_vsize(&tx)).unwrap_or(Amount::MAX);
+        if total > fee {
+            tx.unsigned_tx.output[0].value = total - fee;
+            return Ok(tx);
+        }
+    }
+    bail!("Unable to make transaction because inputs are not enough to pay fee");
+}
+
+/// Rebuild a vote with the same inputs and vote output but a higher fee, so that it can
+/// replace the original in the mempool. prevouts are the outputs spent by the original.
+pub fn bump_vote(
+    ai: &AddrInfo,
+    original: &bitcoin::Transaction,
+    prevouts: &[(OutPoint, Option<TxOut>)],
+    fee_rate: FeeRate,
+) -> Result<psbt::Psbt> {
+    let txid = original.txid();
+    if !original.is_explicitly_rbf() {
+        bail!("Transaction {txid} does not signal replace-by-fee");
+    }
+    let Some(vote) = original.output.iter().find(|txout|Vote::from_txout(txout).is_ok()) else {
+        bail!("Transaction {txid} is not a vote");
+    };
+    let mut spend = Vec::new();
+    for (op, prevout) in prevouts {
+        let Some(txout) = prevout else {
+            bail!("Unable to find the coins spent by {op}");
+        };
+        if txout.script_pubkey != ai.change_script {
+            bail!("Input {op} of transaction {txid} is not paid from {}", ai.addr_str);
+        }
+        spend.push((*op, txout.clone()));
+    }
+    let total: Amount = spend.iter().map(|(_, txout)|txout.value).sum();
+    let Some(old_fee) = total.checked_sub(original.output.iter().map(|txout|txout.value).sum()) else {
+        bail!("Transaction {txid} spends more than its inputs");
+    };
+
+    let mut tx = spend_psbt(ai, &spend, vec![vote.clone()])?;
+    let vsize = estimate_vsize(&tx);
+    let min_fee = old_fee + MIN_BUMP_RATE.fee_vb(vsize).unwrap_or_default();
+    let fee = std::cmp::max(fee_rate.fee_vb(vsize).unwrap_or(Amount::MAX), min_fee);
+    if total <= fee {
+        bail!("Unable to bump fee because inputs are not enough to pay it");
+    }
+    tx.unsigned_tx.output[0].value = total - fee;
+    Ok(tx)
+}
+
+/// Spend the unconfirmed outputs of parent which pay to us back to ourselves, with a fee high
+/// enough that the parent and this child together pay fee_rate, so miners will take both.
+/// unspent are the utxos of our address including unconfirmed ones, parent_prevouts are the
+/// outputs spent by the parent.
+pub fn child_pays_for_parent(
+    ai: &AddrInfo,
+    parent: &bitcoin::Transaction,
+    parent_prevouts: &[(OutPoint, Option<TxOut>)],
+    unspent: &[(OutPoint,TxOut)],
+    fee_rate: FeeRate,
+) -> Result<psbt::Psbt> {
+    let txid = parent.txid();
+    let spend: Vec<_> = unspent.iter().filter(|(op, _)|op.txid == txid).cloned().collect();
+    if spend.is_empty() {
+        bail!("Transaction {txid} has no unspent output paying to {}", ai.addr_str);
+    }
+    let mut parent_in = Amount::ZERO;
+    for (op, prevout) in parent_prevouts {
+        let Some(txout) = prevout else {
+            bail!("Unable to find the coins spent by {op}");
+        };
+        parent_in += txout.value;
+    }
+    let Some(parent_fee) = parent_in.checked_sub(parent.output.iter().map(|txout|txout.value).sum()) else {
+        bail!("Transaction {txid} spends more than its inputs");
+    };
+
+    let mut tx = spend_psbt(ai, &spend, Vec::new())?;
+    let vsize = estimate_vsize(&tx);
+    let package_fee = fee_rate.fee_vb(parent.vsize() as u64 + vsize).unwrap_or(Amount::MAX);
+    let own_fee = fee_rate.fee_vb(vsize).unwrap_or(Amount::MAX);
+    // If the parent already pays enough then we only need to pay for ourselves.
+    let fee = std::cmp::max(package_fee.checked_sub(parent_fee).unwrap_or(Amount::ZERO), own_fee);
+    let total: Amount = spend.iter().map(|(_, txout)|txout.value).sum();
+    if total <= fee {
+        bail!("Unable to make transaction because the change of {txid} is not enough to pay fee");
+    }
+    tx.unsigned_tx.output[0].value = total - fee;
+    Ok(tx)
+}
+
+/// Spend all of the utxos back to our address, along with the other outputs. The change
+/// output pays back the whole amount so the fee must be subtracted from it.
+fn spend_psbt(ai: &AddrInfo, spend: &[(OutPoint,TxOut)], others: Vec<TxOut>) -> Result<psbt::Psbt> {
+    let total: Amount = spend.iter().map(|(_, txout)|txout.value).sum();
+
+    // make pbst
+    let tx = bitcoin::Transaction {
+        version: bitcoin::transaction::Version::TWO,
+        lock_time: bitcoin::absolute::LockTime::ZERO,
+        input: spend.iter().map(|(op, _)|TxIn{
+            previous_output: *op,
+            // Signal replace-by-fee so that the vote can be bumped if it gets stuck
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            ..Default::default()
+        }).collect(),
+        output: [
+            vec![TxOut{
+                value: total,
+                script_pubkey: ai.change_script.clone(),
+            }],
+            others,
+        ].concat(),
+    };
+    let mut tx = psbt::Psbt::from_unsigned_tx(tx)?;
+
+    // update pbst
+    let mut inputs = Vec::new();
+    for (_, txout) in spend {
+        let mut input = Input {
+            witness_utxo: Some(txout.clone()),
+            sighash_type: Some(PsbtSighashType::from_str("SIGHASH_ALL")?),
+            ..Default::default()
+        };
+        // A watch-only PSBT doesn't know the public key, the signer adds it.
+        if let Some(public_key) = &ai.public_key {
+            if txout.script_pubkey.is_p2wpkh() {
+                input.redeem_script = Some({
+                    let Some(wpkh) = public_key.wpubkey_hash() else {
+                        bail!("public_key.wpubkey_hash() did not work correctly");
+                    };
+                    ScriptBuf::new_p2wpkh(&wpkh)
+                });
+            }
+            // Tells signers which key signs this input.
+            input.bip32_derivation.insert(public_key.inner, key_source(ai, public_key));
+        }
+        inputs.push(input);
+    }
+    tx.inputs = inputs;
+
+    // The descriptor knows the scripts and keys which are needed to sign.
+    if let Some(desc) = &ai.descriptor {
+        for i in 0..tx.inputs.len() {
+            if let Err(e) = tx.update_input_with_descriptor(i, desc) {
+                bail!("Unable to add descriptor to input {i}: {e}");
+            }
+        }
+    }
+
+    Ok(tx)
+}
+
+/// The BIP32 origin of the key of the address, a key which was not derived from an extended key
+/// is its own master, so it has its own fingerprint and an empty path.
+fn key_source(ai: &AddrInfo, public_key: &bitcoin::PublicKey) -> KeySource {
+    if let Some(origin) = &ai.key_origin {
+        return origin.clone();
+    }
+    // BIP32 fingerprints are always of the compressed key
+    let hash = bitcoin::PublicKey::new(public_key.inner).pubkey_hash();
+    let mut fingerprint = [0_u8; 4];
+    fingerprint.copy_from_slice(&hash[..4]);
+    (Fingerprint::from(fingerprint), DerivationPath::master())
+}
+
+pub fn sign_txn(ai: &AddrInfo, tx: psbt::Psbt, secp: &Secp256k1<All>) -> Result<bitcoin::Transaction> {
+    finalize_psbt(sign_psbt(ai, tx, secp)?, secp)
+}
+
+/// Add our signatures to the inputs of the PSBT which we can sign, without finalizing it,
+/// a multisig input needs the signatures of the other cosigners as well.
+pub fn sign_psbt(ai: &AddrInfo, mut tx: psbt::Psbt, secp: &Secp256k1<All>) -> Result<psbt::Psbt> {
+
+    let (Some(private_key), Some(public_key)) = (ai.private_key, ai.public_key) else {
+        bail!("Address {} is watch-only, there is no private key to sign with", ai.addr_str);
+    };
+
+    // A PSBT made by a watch-only wallet does not know our public key,
+    // fill it in for every input which is paying from one of our addresses.
+    let ours = key_scripts(&public_key);
+    let prevouts = psbt_prevouts(&tx);
+    for (input, (_, prevout)) in tx.inputs.iter_mut().zip(prevouts) {
+        let Some(prevout) = prevout else { continue };
+        if ours.contains(&prevout.script_pubkey) && input.bip32_derivation.is_empty() {
+            input.bip32_derivation.insert(public_key.inner, key_source(ai, &public_key));
+        }
+    }
+
+    // sign pbst
+    // The signer asks for the compressed form of the key, even when it is uncompressed.
+    let mut key_map = BTreeMap::new();
+    key_map.insert(public_key, private_key);
+    key_map.insert(bitcoin::PublicKey::new(public_key.inner), private_key);
+    match tx.sign(&key_map, secp) {
+        Ok(used) if used.values().all(|keys|keys.is_empty()) => {
+            bail!("None of the inputs of this transaction can be signed by {}", ai.addr_str);
+        }
+        Ok(_) => {
+            eprintln!("Signing succeeded")
+        },
+        Err((_, error_map)) => {
+            let mut errors = Vec::new();
+            for (_, e) in error_map {
+                errors.push(format!("Error: {e}"));
+            }
+            bail!("Failed to sign transaction: {errors:?}");
+        }
+    }
+    Ok(tx)
+}
+
+/// Finalize a PSBT which has all of the signatures it needs and extract the transaction.
+pub fn finalize_psbt(tx: psbt::Psbt, secp: &Secp256k1<All>) -> Result<bitcoin::Transaction> {
+    let tx = match tx.finalize(secp) {
+        Ok(tx) => {
+            eprintln!("Finalizing succeeded");
+            tx
+        }
+        Err((_, errors)) => {
+            bail!("Failed to sign transaction: {errors:?}");
+        }
+    };
+
+    let tx = tx.extract_tx_unchecked_fee_rate();
+    eprintln!("{}", encode::serialize_hex(&tx));
+
+    Ok(tx)
+}
+
+pub fn encode_psbt(tx: &psbt::Psbt) -> String {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::engine::Engine;
+    STANDARD.encode(tx.serialize())
+}
+
+/// Load a PSBT from a file, or from the argument itself if it is not the name of a file.
+pub fn load_psbt(file_or_base64: &str) -> Result<psbt::Psbt> {
+    let path = std::path::Path::new(file_or_base64.trim());
+    if path.is_file() {
+        decode_psbt(&std::fs::read(path)?)
+    } else {
+        decode_psbt(file_or_base64.as_bytes())
+    }
+}
+
+/// Decode a PSBT which is either in binary form or base64 encoded.
+fn decode_psbt(data: &[u8]) -> Result<psbt::Psbt> {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::engine::Engine;
+    if data.starts_with(b"psbt\xff") {
+        return Ok(psbt::Psbt::deserialize(data)?);
+    }
+    let text = String::from_utf8_lossy(data);
+    let bin = STANDARD.decode(text.trim())?;
+    Ok(psbt::Psbt::deserialize(&bin)?)
+}
+
+/// Decode a raw transaction which is either hex encoded or binary.
+pub fn decode_raw_txn(data: &[u8]) -> Result<bitcoin::Transaction> {
+    let text = String::from_utf8_lossy(data);
+    if let Ok(bin) = hex::decode(text.trim()) {
+        return Ok(encode::deserialize(&bin)?);
+    }
+    Ok(encode::deserialize(data)?)
+}
+
+/// Load a transaction from a txid, looking it up with the backend, or from hex or a file.
+pub async fn load_txn(backend: &dyn ChainBackend, arg: &str) -> Result<bitcoin::Transaction> {
+    let arg = arg.trim();
+    if let (false, Ok(txid)) = (std::path::Path::new(arg).is_file(), arg.parse::<bitcoin::Txid>()) {
+        return backend.get_transaction(&txid).await;
+    }
+    load_raw_txn(arg)
+}
+
+/// Load a raw transaction from a file, or from the argument itself if it is not the name of a file.
+pub fn load_raw_txn(file_or_hex: &str) -> Result<bitcoin::Transaction> {
+    let path = std::path::Path::new(file_or_hex.trim());
+    if path.is_file() {
+        decode_raw_txn(&std::fs::read(path)?)
+    } else {
+        decode_raw_txn(file_or_hex.as_bytes())
+    }
+}
+
+/// The fee paid by a PSBT, None if it doesn't know what all of its inputs are spending.
+pub fn psbt_fee(v: &psbt::Psbt) -> Option<Amount> {
+    let mut total = Amount::ZERO;
+    for (_, prevout) in psbt_prevouts(v) {
+        total += prevout?.value;
+    }
+    total.checked_sub(v.unsigned_tx.output.iter().map(|txout|txout.value).sum())
+}
+
+/// The outputs spent by each input of a PSBT, if the PSBT knows them.
+pub fn psbt_prevouts(v: &psbt::Psbt) -> Vec<(OutPoint, Option<TxOut>)> {
+    v.inputs.iter().zip(v.unsigned_tx.input.iter()).map(|(input, txin)| {
+        let op = txin.previous_output;
+        let prevout = input.witness_utxo.clone().or_else(|| {
+            input.non_witness_utxo.as_ref()?.output.get(op.vout as usize).cloned()
+        });
+        (op, prevout)
+    }).collect()
+}
+
+/// Inputs which spend p2pkh outputs must carry the whole previous transaction rather than
+/// only the output, look them up and replace the witness_utxo with it.
+pub async fn add_non_witness_utxos(backend: &dyn ChainBackend, v: &mut psbt::Psbt) -> Result<()> {
+    for (input, txin) in v.inputs.iter_mut().zip(v.unsigned_tx.input.iter()) {
+        let Some(wutxo) = &input.witness_utxo else { continue };
+        if wutxo.script_pubkey.is_witness_program() {
+            continue;
+        }
+        let op = txin.previous_output;
+        let prev = backend.get_transaction(&op.txid).await?;
+        if prev.txid() != op.txid || prev.output.get(op.vout as usize) != Some(wutxo) {
+            bail!("Previous transaction {} does not match the coins spent by {op}", op.txid);
+        }
+        input.non_witness_utxo = Some(prev);
+        input.witness_utxo = None;
+    }
+    Ok(())
+}
+
+/// Whether every coin spent by the transaction is still unspent,
+/// false means that another transaction has spent at least one of them.
+pub async fn inputs_unspent(backend: &dyn ChainBackend, tx: &bitcoin::Transaction) -> Result<bool> {
+    let mut utxos_by_addr = BTreeMap::new();
+    for (op, prevout) in fetch_prevouts(backend, tx).await {
+        let Some(prevout) = prevout else {
+            bail!("Unable to find the coins spent by {op}");
+        };
+        let (addr, _) = crate::address::encode_from_pkscript(&prevout.script_pubkey);
+        if !utxos_by_addr.contains_key(&addr) {
+            let utxos = backend.get_utxos(&addr, true).await?;
+            utxos_by_addr.insert(addr.clone(), utxos);
+        }
+        if !utxos_by_addr[&addr].iter().any(|(utxo, _)|*utxo == op) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Where a transaction which was sent has got to
+pub enum Tracked {
+    /// The backend has not seen it yet
+    Waiting,
+    Mempool,
+    Confirmed{ height: u32, confirmations: u32 },
+    /// Neither in the mempool nor in a block, with whether its coins are still unspent
+    Gone(Result<bool>),
+}
+
+/// How many times the backend may not find a transaction which was just sent before it is
+/// taken to be gone, rather than the backend being slow to see it
+const NOT_FOUND_POLLS: u32 = 4;
+
+/// Follows a transaction which was sent, by polling the backend
+pub struct Tracker {
+    txn: bitcoin::Transaction,
+    seen: bool,
+    not_found: u32,
+}
+
+impl Tracker {
+    pub fn new(txn: bitcoin::Transaction) -> Self {
+        Self{ txn, seen: false, not_found: 0 }
+    }
+
+    pub async fn poll(&mut self, backend: &dyn ChainBackend) -> Result<Tracked> {
+        let status = backend.get_tx_status(&self.txn.txid()).await?;
+        Ok(match status {
+            TxStatus::Mempool => {
+                self.seen = true;
+                Tracked::Mempool
+            }
+            TxStatus::Confirmed{ height, confirmations } => {
+                self.seen = true;
+                Tracked::Confirmed{ height, confirmations }
+            }
+            TxStatus::NotFound => {
+                self.not_found += 1;
+                if !self.seen && self.not_found < NOT_FOUND_POLLS {
+                    Tracked::Waiting
+                } else {
+                    Tracked::Gone(inputs_unspent(backend, &self.txn).await)
+                }
+            }
+        })
+    }
+}
+
+/// Look up the outputs spent by each input of a transaction, None if the lookup failed.
+pub async fn fetch_prevouts(
+    backend: &dyn ChainBackend,
+    tx: &bitcoin::Transaction,
+) -> Vec<(OutPoint, Option<TxOut>)> {
+    let mut out = Vec::new();
+    for txin in tx.input.iter() {
+        let op = txin.previous_output;
+        let prevout = match backend.get_transaction(&op.txid).await {
+            Ok(prev) => prev.output.get(op.vout as usize).cloned(),
+            Err(e) => {
+                eprintln!("Unable to get previous transaction {}: {e}", op.txid);
+                None
+            }
+        };
+        out.push((op, prevout));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+
+    fn test_address() -> AddrInfo {
+        let sk = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+        let sk = bitcoin::PrivateKey::new(sk, bitcoin::Network::Bitcoin);
+        key_to_address(sk, &Secp256k1::new()).unwrap()
+    }
+
+    /// A transaction paying each of the amounts to the address, spending a made up coin n
+    fn funding(ai: &AddrInfo, n: u8, amounts: &[u64]) -> bitcoin::Transaction {
+        bitcoin::Transaction{
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![TxIn{
+                previous_output: OutPoint::new(bitcoin::Txid::from_byte_array([n; 32]), 0),
+                ..Default::default()
+            }],
+            output: amounts.iter().map(|a|TxOut{
+                value: Amount::from_sat(*a),
+                script_pubkey: ai.change_script.clone(),
+            }).collect(),
+        }
+    }
+
+    fn utxos(txn: &bitcoin::Transaction) -> Vec<(OutPoint,TxOut)> {
+        txn.output.iter().enumerate()
+            .map(|(i, txout)|(OutPoint::new(txn.txid(), i as u32), txout.clone())).collect()
+    }
+
+    #[tokio::test]
+    async fn prevouts_come_from_the_backend() {
+        let ai = test_address();
+        let backend = MockBackend::default();
+        let fund = funding(&ai, 1, &[1000, 2000]);
+        backend.add(fund.clone(), Some(1));
+        let mut spend = funding(&ai, 2, &[500]);
+        spend.input = utxos(&fund).iter().map(|(op, _)|TxIn{ previous_output: *op, ..Default::default() }).collect();
+        spend.input.push(TxIn{
+            previous_output: OutPoint::new(bitcoin::Txid::from_byte_array([3; 32]), 0),
+            ..Default::default()
+        });
+        let prevouts = fetch_prevouts(&backend, &spend).await;
+        assert_eq!(prevouts.len(), 3);
+        let want: Vec<_> = utxos(&fund).into_iter().map(|(op, txout)|(op, Some(txout))).collect();
+        assert_eq!(prevouts[..2], want[..]);
+        assert_eq!(prevouts[2].1, None);
+    }
+
+    #[tokio::test]
+    async fn address_utxos_are_confirmed_and_unspent() {
+        let ai = test_address();
+        let backend = MockBackend::default();
+        assert!(get_address_utxos(&backend, &ai.addr_str).await.is_err());
+
+        let confirmed = funding(&ai, 1, &[1000, 2000]);
+        let unconfirmed = funding(&ai, 2, &[3000]);
+        backend.add(confirmed.clone(), Some(10));
+        backend.add(unconfirmed, None);
+        let got = get_address_utxos(&backend, &ai.addr_str).await.unwrap();
+        assert_eq!(got, utxos(&confirmed));
+
+        let mut spend = funding(&ai, 3, &[500]);
+        spend.input[0].previous_output = OutPoint::new(confirmed.txid(), 0);
+        backend.add(spend.clone(), Some(11));
+        let got = get_address_utxos(&backend, &ai.addr_str).await.unwrap();
+        assert_eq!(got, vec![utxos(&confirmed)[1].clone(), utxos(&spend)[0].clone()]);
+    }
+
+    #[test]
+    fn make_vote_spends_the_biggest_coin() {
+        let ai = test_address();
+        let coins = utxos(&funding(&ai, 1, &[100_000, 5_000_000, 2_000_000]));
+        let psbt = make_vote(&ai, &coins, false, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+        let tx = &psbt.unsigned_tx;
+        assert_eq!(tx.input.len(), 1);
+        assert_eq!(tx.input[0].previous_output, coins[1].0);
+        let fee = DEFAULT_FEE_RATE.fee_vb(estimate_vsize(&psbt)).unwrap();
+        assert_eq!(tx.output[0].value, Amount::from_sat(5_000_000) - fee);
+        assert_eq!(Vote::from_txout(&tx.output[1]).unwrap(), Vote::default());
+    }
+
+    #[test]
+    fn make_vote_adds_coins_until_the_fee_is_paid() {
+        let ai = test_address();
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(10);
+        let one_input = fee_rate.fee_vb(estimate_vsize(
+            &spend_psbt(&ai, &utxos(&funding(&ai, 1, &[0])), vec![Vote::default().encode()]).unwrap()
+        )).unwrap();
+        // Each coin is worth spending but none can pay the fee alone
+        let value = one_input.to_sat() - 1;
+        let coins = utxos(&funding(&ai, 1, &[value, value, value]));
+        let psbt = make_vote(&ai, &coins, false, fee_rate, &Vote::default()).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 2);
+    }
+
+    #[test]
+    fn make_vote_skips_dust() {
+        let ai = test_address();
+        let dust = fee_rate_input_fee(&ai, DEFAULT_FEE_RATE).to_sat();
+        let coins = utxos(&funding(&ai, 1, &[dust, 1_000_000, dust / 2]));
+        let psbt = make_vote(&ai, &coins, true, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output, coins[1].0);
+
+        let coins = utxos(&funding(&ai, 1, &[dust, dust / 2]));
+        assert!(make_vote(&ai, &coins, false, DEFAULT_FEE_RATE, &Vote::default()).is_err());
+    }
+
+    #[test]
+    fn make_vote_consolidates() {
+        let ai = test_address();
+        let coins = utxos(&funding(&ai, 1, &[1_000_000, 2_000_000, 3_000_000]));
+        let psbt = make_vote(&ai, &coins, true, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 3);
+        let fee = DEFAULT_FEE_RATE.fee_vb(estimate_vsize(&psbt)).unwrap();
+        assert_eq!(psbt.unsigned_tx.output[0].value, Amount::from_sat(6_000_000) - fee);
+    }
+
+    fn fee_rate_input_fee(ai: &AddrInfo, fee_rate: FeeRate) -> Amount {
+        fee_rate.fee_vb(input_vsize(ai).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn inputs_unspent_detects_double_spend() {
+        let ai = test_address();
+        let backend = MockBackend::default();
+        let fund = funding(&ai, 1, &[1_000_000]);
+        backend.add(fund.clone(), Some(1));
+        let vote = make_vote(&ai, &utxos(&fund), false, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+        let vote = vote.unsigned_tx;
+        assert!(inputs_unspent(&backend, &vote).await.unwrap());
+
+        let mut other = funding(&ai, 2, &[900_000]);
+        other.input[0].previous_output = vote.input[0].previous_output;
+        backend.add(other, None);
+        assert!(!inputs_unspent(&backend, &vote).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn tracker_checks_for_double_spends() {
+        let ai = test_address();
+        let backend = MockBackend::default();
+        let fund = funding(&ai, 1, &[1_000_000]);
+        backend.add(fund.clone(), Some(1));
+        let vote = make_vote(&ai, &utxos(&fund), false, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+
+        // Never seen, after a while it is gone but can be sent again
+        let mut tracker = Tracker::new(vote.unsigned_tx.clone());
+        for _ in 1..NOT_FOUND_POLLS {
+            assert!(matches!(tracker.poll(&backend).await.unwrap(), Tracked::Waiting));
+        }
+        assert!(matches!(tracker.poll(&backend).await.unwrap(), Tracked::Gone(Ok(true))));
+
+        // Seen and then gone is checked at once
+        let mut tracker = Tracker::new(vote.unsigned_tx.clone());
+        backend.add(vote.unsigned_tx.clone(), None);
+        assert!(matches!(tracker.poll(&backend).await.unwrap(), Tracked::Mempool));
+        backend.txns.lock().unwrap().retain(|(txn, _)|txn.txid() != vote.unsigned_tx.txid());
+        let mut other = funding(&ai, 2, &[900_000]);
+        other.input[0].previous_output = vote.unsigned_tx.input[0].previous_output;
+        backend.add(other, Some(2));
+        assert!(matches!(tracker.poll(&backend).await.unwrap(), Tracked::Gone(Ok(false))));
+
+        // Never seen and double-spent
+        let mut tracker = Tracker::new(vote.unsigned_tx);
+        for _ in 1..NOT_FOUND_POLLS {
+            assert!(matches!(tracker.poll(&backend).await.unwrap(), Tracked::Waiting));
+        }
+        assert!(matches!(tracker.poll(&backend).await.unwrap(), Tracked::Gone(Ok(false))));
+    }
+
+    /// A vote spending all of the coins, and the prevouts of its inputs
+    fn vote_of(ai: &AddrInfo, coins: &[(OutPoint,TxOut)], fee_rate: FeeRate)
+        -> (bitcoin::Transaction, Vec<(OutPoint, Option<TxOut>)>)
+    {
+        let vote = make_vote(ai, coins, true, fee_rate, &Vote::default()).unwrap().unsigned_tx;
+        let prevouts = coins.iter().map(|(op, txout)|(*op, Some(txout.clone()))).collect();
+        (vote, prevouts)
+    }
+
+    #[test]
+    fn bump_vote_pays_at_least_the_minimum_increase() {
+        let ai = test_address();
+        let coins = utxos(&funding(&ai, 1, &[1_000_000, 2_000_000]));
+        let (original, prevouts) = vote_of(&ai, &coins, DEFAULT_FEE_RATE);
+        let old_fee = Amount::from_sat(3_000_000) - original.output.iter().map(|txout|txout.value).sum();
+
+        // The same fee rate still has to pay 1 unit per vbyte more than the original (BIP125)
+        let psbt = bump_vote(&ai, &original, &prevouts, DEFAULT_FEE_RATE).unwrap();
+        let vsize = estimate_vsize(&psbt);
+        assert_eq!(psbt_fee(&psbt).unwrap(), old_fee + MIN_BUMP_RATE.fee_vb(vsize).unwrap());
+        assert_eq!(psbt.unsigned_tx.output[1], original.output[1]);
+
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(50);
+        let psbt = bump_vote(&ai, &original, &prevouts, fee_rate).unwrap();
+        assert_eq!(psbt_fee(&psbt).unwrap(), fee_rate.fee_vb(vsize).unwrap());
+    }
+
+    #[test]
+    fn bump_vote_errors() {
+        let ai = test_address();
+        let coins = utxos(&funding(&ai, 1, &[1_000_000]));
+        let (original, prevouts) = vote_of(&ai, &coins, DEFAULT_FEE_RATE);
+
+        let mut final_tx = original.clone();
+        final_tx.input[0].sequence = Sequence::MAX;
+        let err = bump_vote(&ai, &final_tx, &prevouts, DEFAULT_FEE_RATE).unwrap_err().to_string();
+        assert!(err.contains("replace-by-fee"), "{err}");
+
+        let mut not_vote = original.clone();
+        not_vote.output.truncate(1);
+        let err = bump_vote(&ai, &not_vote, &prevouts, DEFAULT_FEE_RATE).unwrap_err().to_string();
+        assert!(err.contains("not a vote"), "{err}");
+
+        let sk = secp256k1::SecretKey::from_slice(&[2; 32]).unwrap();
+        let other = key_to_address(bitcoin::PrivateKey::new(sk, bitcoin::Network::Bitcoin), &Secp256k1::new()).unwrap();
+        let err = bump_vote(&other, &original, &prevouts, DEFAULT_FEE_RATE).unwrap_err().to_string();
+        assert!(err.contains("is not paid from"), "{err}");
+    }
+
+    #[test]
+    fn cpfp_pays_the_shortfall_of_the_package() {
+        let ai = test_address();
+        let coins = utxos(&funding(&ai, 1, &[1_000_000]));
+        let (parent, prevouts) = vote_of(&ai, &coins, FeeRate::from_sat_per_vb_unchecked(1));
+        let parent_fee = Amount::from_sat(1_000_000) - parent.output.iter().map(|txout|txout.value).sum();
+        // Only the change pays to us, not the vote output
+        let unspent: Vec<_> = utxos(&parent).into_iter()
+            .filter(|(_, txout)|txout.script_pubkey == ai.change_script).collect();
+
+        let fee_rate = FeeRate::from_sat_per_vb_unchecked(20);
+        let psbt = child_pays_for_parent(&ai, &parent, &prevouts, &unspent, fee_rate).unwrap();
+        assert_eq!(psbt.unsigned_tx.input.len(), 1);
+        assert_eq!(psbt.unsigned_tx.input[0].previous_output, unspent[0].0);
+        let vsize = estimate_vsize(&psbt);
+        let package_fee = fee_rate.fee_vb(parent.vsize() as u64 + vsize).unwrap();
+        assert_eq!(psbt_fee(&psbt).unwrap(), package_fee - parent_fee);
+
+        // The parent already pays more than enough, so the child only pays for itself
+        let psbt = child_pays_for_parent(&ai, &parent, &prevouts, &unspent, MIN_BUMP_RATE).unwrap();
+        assert_eq!(psbt_fee(&psbt).unwrap(), MIN_BUMP_RATE.fee_vb(estimate_vsize(&psbt)).unwrap());
+    }
+
+    #[test]
+    fn cpfp_needs_an_output_of_the_parent() {
+        let ai = test_address();
+        let coins = utxos(&funding(&ai, 1, &[1_000_000]));
+        let (parent, prevouts) = vote_of(&ai, &coins, DEFAULT_FEE_RATE);
+        let err = child_pays_for_parent(&ai, &parent, &prevouts, &coins, DEFAULT_FEE_RATE)
+            .unwrap_err().to_string();
+        assert!(err.contains("has no unspent output"), "{err}");
+    }
+
+    fn wif_of(data: &[u8]) -> String {
+        bitcoin::base58::encode_check(data)
+    }
+
+    #[test]
+    fn wif_round_trip() {
+        for compressed in [true, false] {
+            let sk = bitcoin::PrivateKey{
+                compressed,
+                network: bitcoin::Network::Bitcoin,
+                inner: secp256k1::SecretKey::from_slice(&[1; 32]).unwrap(),
+            };
+            let wif = encode_wif(&sk);
+            assert_eq!(decode_wif(&wif).unwrap(), sk);
+            assert_eq!(decode_wif(&format!(" {wif}\n")).unwrap(), sk);
+        }
+    }
+
+    #[test]
+    fn wif_errors() {
+        let prefix = network::current().wif_prefix;
+        let err = |data: &[u8]|decode_wif(&wif_of(data)).unwrap_err().to_string();
+
+        let e = err(&[&[0x80][..], &[1; 32], &[1]].concat());
+        assert!(e.contains("Bitcoin private key"), "{e}");
+        let e = err(&[&[0x42][..], &[1; 32], &[1]].concat());
+        assert!(e.contains("version byte is 0x42"), "{e}");
+        let e = err(&[&[prefix][..], &[1; 32], &[2]].concat());
+        assert!(e.contains("compression flag is 0x02"), "{e}");
+        let e = err(&[&[prefix][..], &[1; 31]].concat());
+        assert!(e.contains("31 bytes"), "{e}");
+        let e = err(&[&[prefix][..], &[1; 32], &[1, 1]].concat());
+        assert!(e.contains("34 bytes"), "{e}");
+        assert!(decode_wif("not a key").is_err());
+    }
+
+    #[test]
+    fn wif_prefix_is_the_same_on_every_network() {
+        for params in [&network::MAINNET, &network::TESTNET, &network::REGTEST] {
+            assert_eq!(params.wif_prefix, 0xef, "{}", params.name);
+        }
+        // So there is no error to give for it, whichever network is in use
+        assert!(!OTHER_WIF_PREFIXES.iter().any(|(v, _)|*v == 0xef));
+        assert!(decode_wif(&wif_of(&[&[0xef][..], &[1; 32], &[1]].concat())).is_ok());
+    }
+
+    #[test]
+    fn key_origin_of_a_wif_key() {
+        let secp = Secp256k1::new();
+        let inner = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+        let compressed = bitcoin::PrivateKey::new(inner, bitcoin::Network::Bitcoin);
+        let hash = compressed.public_key(&secp).pubkey_hash();
+        let expected = (Fingerprint::from(<[u8; 4]>::try_from(&hash[..4]).unwrap()), DerivationPath::master());
+        let uncompressed = bitcoin::PrivateKey::new_uncompressed(inner, bitcoin::Network::Bitcoin);
+        for sk in [compressed, uncompressed] {
+            for ai in key_to_addresses(sk, &secp) {
+                let coins = utxos(&funding(&ai, 1, &[1_000_000]));
+                let psbt = make_vote(&ai, &coins, false, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+                let derivation = &psbt.inputs[0].bip32_derivation;
+                assert_eq!(derivation.get(&inner.public_key(&secp)), Some(&expected), "{}", ai.addr_str);
+            }
+        }
+    }
+
+    #[test]
+    fn key_origin_of_a_seed_key() {
+        let secp = Secp256k1::new();
+        let words = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+        let (account, origin) = crate::seed::account_key(words, "", None, &secp).unwrap();
+        let (path, mut addrs) = crate::seed::derive_address(&account, &origin, 0, 3, &secp).unwrap();
+        let ai = addrs.remove(0);
+        assert_eq!(path.to_string(), "m/84'/390'/0'/0/3");
+        let seed = bip39::Mnemonic::parse(words).unwrap().to_seed("");
+        let master = bitcoin::bip32::Xpriv::new_master(bitcoin::Network::Bitcoin, &seed).unwrap();
+
+        let coins = utxos(&funding(&ai, 1, &[1_000_000]));
+        let psbt = make_vote(&ai, &coins, false, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+        let public_key = ai.public_key.unwrap().inner;
+        assert_eq!(psbt.inputs[0].bip32_derivation.get(&public_key), Some(&(master.fingerprint(&secp), path)));
+    }
+
+    #[test]
+    fn watch_only_vote_is_signed_offline() {
+        let secp = Secp256k1::new();
+        let ai = test_address();
+        let watch_only = watch_only_address(&ai.addr_str).unwrap();
+        let coins = utxos(&funding(&ai, 1, &[1_000_000]));
+        let psbt = make_vote(&watch_only, &coins, false, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+        assert!(psbt.inputs[0].bip32_derivation.is_empty());
+        let err = sign_psbt(&watch_only, psbt.clone(), &secp).unwrap_err().to_string();
+        assert!(err.contains("watch-only"), "{err}");
+
+        // Carried to the offline computer as base64
+        let psbt = load_psbt(&encode_psbt(&psbt)).unwrap();
+        let tx = sign_txn(&ai, psbt.clone(), &secp).unwrap();
+        assert_eq!(tx.input[0].witness.len(), 2);
+        assert_eq!(tx.output, psbt.unsigned_tx.output);
+
+        let sk = secp256k1::SecretKey::from_slice(&[2; 32]).unwrap();
+        let other = key_to_address(bitcoin::PrivateKey::new(sk, bitcoin::Network::Bitcoin), &secp).unwrap();
+        let err = sign_psbt(&other, psbt, &secp).unwrap_err().to_string();
+        assert!(err.contains("None of the inputs"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn legacy_vote_from_an_uncompressed_key() {
+        let secp = Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+        let sk = bitcoin::PrivateKey::new_uncompressed(sk, bitcoin::Network::Bitcoin);
+        let addrs = key_to_addresses(sk, &secp);
+        assert_eq!(addrs.len(), 1);
+        let ai = &addrs[0];
+        assert!(ai.change_script.is_p2pkh());
+
+        let backend = MockBackend::default();
+        let fund = funding(ai, 1, &[1_000_000]);
+        backend.add(fund.clone(), Some(1));
+        let mut psbt = make_vote(ai, &utxos(&fund), false, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+        add_non_witness_utxos(&backend, &mut psbt).await.unwrap();
+        assert_eq!(psbt.inputs[0].non_witness_utxo, Some(fund));
+        assert_eq!(psbt.inputs[0].witness_utxo, None);
+
+        let tx = sign_txn(ai, psbt.clone(), &secp).unwrap();
+        assert!(tx.input[0].witness.is_empty());
+        // The signature and the 65 byte uncompressed public key
+        let pushes: Vec<_> = tx.input[0].script_sig.instructions().collect::<Result<_, _>>().unwrap();
+        assert_eq!(pushes.len(), 2);
+        assert_eq!(pushes[1].push_bytes().unwrap().len(), 65);
+        let vsize = tx.vsize() as u64;
+        assert!(estimate_vsize(&psbt) >= vsize, "estimated {} < {vsize}", estimate_vsize(&psbt));
+    }
+
+    #[tokio::test]
+    async fn non_witness_utxo_must_match() {
+        let secp = Secp256k1::new();
+        let sk = secp256k1::SecretKey::from_slice(&[1; 32]).unwrap();
+        let ai = key_to_addresses(bitcoin::PrivateKey::new(sk, bitcoin::Network::Bitcoin), &secp).remove(1);
+        assert!(ai.change_script.is_p2pkh());
+        let fund = funding(&ai, 1, &[1_000_000]);
+        let vote = make_vote(&ai, &utxos(&fund), false, DEFAULT_FEE_RATE, &Vote::default()).unwrap();
+
+        // A different transaction with the same output is not the one being spent
+        let backend = MockBackend::default();
+        backend.forged.lock().unwrap().push((fund.txid(), funding(&ai, 2, &[1_000_000])));
+        let err = add_non_witness_utxos(&backend, &mut vote.clone()).await.unwrap_err().to_string();
+        assert!(err.contains("does not match"), "{err}");
+
+        // Or the transaction is right but the PSBT lies about the amount
+        let backend = MockBackend::default();
+        backend.add(fund, Some(1));
+        let mut psbt = vote;
+        psbt.inputs[0].witness_utxo.as_mut().unwrap().value = Amount::from_sat(2_000_000);
+        let err = add_non_witness_utxos(&backend, &mut psbt).await.unwrap_err().to_string();
+        assert!(err.contains("does not match"), "{err}");
+    }
+}